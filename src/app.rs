@@ -1,55 +1,94 @@
 use crate::prelude::*;
 
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
 
 pub struct App {
-    window: Window,
-
-    // passed to RingBuffer and Window, then unused here so far
-    options: Arc<Mutex<Options>>,
-
-    // input logfiles. not used yet but may want to close/cleanup?
-    logfiles: Vec<Logfile>,
+    output: Output,
 }
 
 impl App {
-    pub fn new() -> Result<Self, Error> {
-        let mut options = Options::new()?;
-        Self::setup_logger(&options.debug)?;
-
-        debug!("{:?}", options);
-
-        // TODO: should probably abort process when a thread crashes?
-        log_panics::init();
-
-        let (request_tx, request_rx): (Sender<Request>, Receiver<Request>) = unbounded();
-
-        let logfiles = options
-            .file
-            .drain(..)
-            .map(|file| Logfile::new(file, request_tx.clone()))
-            .collect::<Result<_, _>>()?;
+    pub fn new(mut options: Options) -> Result<Self, Error> {
+        let log_format = Arc::new(LogFormat::from_options(&options)?);
+
+        let clock = crate::clock::from_options(&options);
+
+        // when replaying, readers only need to stay a little ahead of the
+        // clock, rather than loading whole logfiles into the channel
+        let (request_tx, request_rx): (Sender<Request>, Receiver<Request>) =
+            if options.replay.is_some() {
+                bounded(10_000)
+            } else {
+                unbounded()
+            };
+
+        let inputs = Inputs::new(
+            options.file.drain(..).collect(),
+            options.start_from()?,
+            log_format,
+            request_tx,
+            Arc::clone(&clock),
+        )?;
+        inputs.watch()?;
 
         let options = Arc::new(Mutex::new(options));
         let alltime_stats = Arc::new(Mutex::new(Stats::new()));
-        let ring_buffer = Arc::new(Mutex::new(RingBuffer::new(Arc::clone(&options), true)?));
+        let ring_buffer = Arc::new(Mutex::new(RingBuffer::new(Arc::clone(&options), Some(0))?));
+        let prefilter = Prefilter::from_options(&options.lock().unwrap());
         Self::start_request_receiver(
             request_rx,
+            prefilter,
+            Arc::clone(&clock),
             Arc::clone(&alltime_stats),
             Arc::clone(&ring_buffer),
         )?;
 
-        // do this last so any errors in setting up the rest of the app are displayed
-        let window = Window::new(Arc::clone(&options), alltime_stats, ring_buffer);
+        let metrics = options.lock().unwrap().metrics;
+        if let Some(addr) = metrics {
+            Metrics::new(
+                Arc::clone(&options),
+                Arc::clone(&clock),
+                Arc::clone(&alltime_stats),
+                Arc::clone(&ring_buffer),
+            )
+            .serve(addr)?;
+        }
+
+        {
+            let o = options.lock().unwrap();
+            if let Some(ref addr) = o.statsd {
+                let interval = std::time::Duration::from_millis((o.interval * 1000.0) as u64);
+                Statsd::new(
+                    addr,
+                    &o.statsd_prefix,
+                    o.statsd_tag.clone(),
+                    Arc::clone(&alltime_stats),
+                )?
+                .start(interval)?;
+            }
+        } // lock on options
+
+        let batch = options.lock().unwrap().batch;
+        let output = if batch {
+            Output::Batch(Batch::new(
+                Arc::clone(&options),
+                clock,
+                alltime_stats,
+                ring_buffer,
+            ))
+        } else {
+            // do this last so any errors in setting up the rest of the app are displayed
+            Output::Window(Box::new(Window::new(
+                Arc::clone(&options),
+                clock,
+                alltime_stats,
+                ring_buffer,
+            )?))
+        };
 
-        Ok(App {
-            options,
-            logfiles,
-            window,
-        })
+        Ok(App { output })
     }
 
-    fn setup_logger(path: &std::path::PathBuf) -> Result<(), fern::InitError> {
+    pub fn setup_logger(path: &std::path::PathBuf) -> Result<(), fern::InitError> {
         fern::Dispatch::new()
             .format(|out, message, record| {
                 out.finish(format_args!(
@@ -67,14 +106,20 @@ impl App {
     }
 
     pub fn start(&mut self) -> Result<(), Error> {
-        self.window.run()?;
+        match self.output {
+            Output::Window(ref mut window) => window.run()?,
+            Output::Batch(ref mut batch) => batch.run()?,
+        }
         Ok(())
     }
 
     // thread to receive Request methods from each Logfile instance
-    // and append to alltime_stats and ring_buffer
+    // and append to alltime_stats and ring_buffer, dropping any the prefilter
+    // doesn't want, then waiting on the clock if we're replaying
     fn start_request_receiver(
         request_rx: Receiver<Request>,
+        prefilter: Prefilter,
+        clock: Arc<dyn Clock>,
         alltime_stats: Arc<Mutex<Stats>>,
         ring_buffer: Arc<Mutex<RingBuffer>>,
     ) -> Result<thread::JoinHandle<Result<(), Error>>, Error> {
@@ -82,6 +127,14 @@ impl App {
             for request in request_rx {
                 //debug!("Request is {:?}", request);
 
+                // before waiting, so dropped requests don't pace a replay
+                if !prefilter.keep(&request) {
+                    ring_buffer.lock().unwrap().dropped += 1;
+                    continue;
+                }
+
+                clock.wait_until(request.time.into());
+
                 {
                     let mut alltime_stats = alltime_stats.lock().unwrap();
                     alltime_stats.add_request(&request);
@@ -101,3 +154,9 @@ impl App {
             .spawn(c)?)
     }
 }
+
+// where stats are shown: the live terminal display, or plain text for --batch
+enum Output {
+    Window(Box<Window>),
+    Batch(Batch),
+}
@@ -0,0 +1,450 @@
+use crate::prelude::*;
+
+use crate::stats::Counters;
+use crate::window::StartedAt;
+
+use serde_json::{json, Value};
+
+use std::io::Write;
+
+const CARGO_PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Non-interactive output for --batch, like top -b: the same stats as Window,
+// printed to stdout every interval as plain text, JSON lines or CSV.
+pub struct Batch {
+    started_at: StartedAt,
+
+    options: Arc<Mutex<Options>>,
+    clock: Arc<dyn Clock>,
+
+    alltime_stats: Arc<Mutex<Stats>>,
+    ring_buffer: Arc<Mutex<RingBuffer>>,
+}
+
+// Everything printed for one interval, borrowed from the locked stats.
+struct Snapshot<'a> {
+    now: chrono::DateTime<chrono::Utc>,
+    late: u64,
+    dropped: u64,
+
+    // see RingBuffer::memory
+    memory: usize,
+
+    alltime: &'a Stats,
+    alltime_elapsed: f64,
+
+    window: &'a Stats,
+    window_elapsed: f64,
+
+    group_by: GroupBy,
+    columns: Vec<Column>,
+    rows: Vec<(&'a GroupKey, &'a RingBuffer)>,
+}
+
+impl Batch {
+    pub fn new(
+        options: Arc<Mutex<Options>>,
+        clock: Arc<dyn Clock>,
+        alltime_stats: Arc<Mutex<Stats>>,
+        ring_buffer: Arc<Mutex<RingBuffer>>,
+    ) -> Self {
+        Batch {
+            started_at: StartedAt(Arc::clone(&clock)),
+            options,
+            clock,
+            alltime_stats,
+            ring_buffer,
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        let options = self.options.lock().unwrap();
+        let interval = std::time::Duration::from_millis((options.interval * 1000.0) as u64);
+        let iterations = options.iterations;
+        let format = options.output.unwrap_or(OutputFormat::Text);
+        drop(options);
+
+        if format == OutputFormat::Csv {
+            println!("{}", Self::csv_header());
+        }
+
+        // wait before the first print too, so backfilled lines have been read
+        let mut printed = 0;
+        while iterations.is_none_or(|i| printed < i) {
+            thread::sleep(interval);
+
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            self.print(&mut stdout, format)?;
+            stdout.flush()?;
+
+            printed += 1;
+        }
+
+        Ok(())
+    }
+
+    fn print(&self, out: &mut impl Write, format: OutputFormat) -> Result<(), Error> {
+        use lazysort::SortedBy;
+
+        let now = self.clock.now();
+
+        // copied, so only one lock is held at a time
+        let alltime_stats = self.alltime_stats.lock().unwrap().clone();
+
+        let mut ring_buffer = self.ring_buffer.lock().unwrap();
+        ring_buffer.cleanup(now)?;
+
+        let options = self.options.lock().unwrap();
+        let (group_by, top) = (options.group(), options.top);
+        let columns = Column::shown(&options.columns, &ring_buffer.stats);
+        drop(options);
+
+        // same ordering as Window, see impl Ord for RingBuffer
+        let rows = match &ring_buffer.grouped {
+            Some(grouped) => grouped
+                .iter()
+                .filter(|(_, v)| !v.buffer.is_empty())
+                .collect::<Vec<(&GroupKey, &RingBuffer)>>()
+                .into_iter()
+                .sorted_by(|a, b| b.1.cmp(a.1))
+                .take(top)
+                .collect(),
+            None => vec![],
+        };
+
+        let snapshot = Snapshot {
+            now,
+            late: ring_buffer.late,
+            dropped: ring_buffer.dropped,
+            memory: ring_buffer.memory() + ring_buffer.request_memory(),
+            alltime: &alltime_stats,
+            alltime_elapsed: std::cmp::max(self.started_at.elapsed().as_secs(), 1) as f64,
+            window: &ring_buffer.stats,
+            window_elapsed: ring_buffer.elapsed(now),
+            group_by,
+            columns,
+            rows,
+        };
+
+        match format {
+            OutputFormat::Text => self.print_text(out, &snapshot),
+            OutputFormat::Json => Self::print_json(out, &snapshot),
+            OutputFormat::Csv => Self::print_csv(out, &snapshot),
+        }
+    }
+
+    fn print_text(&self, out: &mut impl Write, s: &Snapshot<'_>) -> Result<(), Error> {
+        write!(
+            out,
+            "apachetop {} at {}, {}",
+            CARGO_PKG_VERSION,
+            s.now
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S"),
+            self.started_at
+        )?;
+        if s.late > 0 {
+            write!(out, ", late: {}", s.late)?;
+        }
+        if s.dropped > 0 {
+            write!(out, ", dropped: {}", s.dropped)?;
+        }
+        write!(out, ", mem: {}", Window::humansize(s.memory as f64).trim())?;
+        writeln!(out)?;
+
+        Self::print_stats(out, "All:", s.alltime, s.alltime_elapsed)?;
+        Self::print_stats(out, "R:", s.window, s.window_elapsed)?;
+
+        let time_line = Window::time_line(s.window);
+        if !time_line.is_empty() {
+            writeln!(out, "{}", time_line)?;
+        }
+
+        writeln!(out, "{} {}", Window::table_header(&s.columns), s.group_by)?;
+        for (key, rb) in s.rows.iter() {
+            let stats = Window::table_stats(&s.columns, rb, s.window_elapsed, s.now);
+            writeln!(out, "{} {}", stats, key)?;
+        }
+
+        writeln!(out)?;
+
+        Ok(())
+    }
+
+    // All:  638924 reqs (182.65/sec) 3.27G (981.60K/sec) 5.37K/req
+    // 2xx: 455415 (71.3%) 3xx: 175745 (27.5%) 4xx: 7746 (1.2%) 5xx: 10 (0.0%)
+    fn print_stats(
+        out: &mut impl Write,
+        header: &str,
+        stats: &Stats,
+        elapsed: f64,
+    ) -> Result<(), Error> {
+        let global = &stats.global;
+
+        writeln!(
+            out,
+            "{:5} {} reqs ({:.2}/sec) {} ({}/sec) {}/req",
+            header,
+            global.requests,
+            global.requests as f64 / elapsed,
+            Window::humansize(global.bytes as f64).trim(),
+            Window::humansize(global.bytes as f64 / elapsed).trim(),
+            Window::humansize(global.bytes as f64 / std::cmp::max(global.requests, 1) as f64)
+                .trim(),
+        )?;
+
+        let classes: Vec<String> = ["2xx", "3xx", "4xx", "5xx"]
+            .iter()
+            .enumerate()
+            .map(|(i, class)| {
+                let requests = stats.by_status_code[i + 2].requests;
+                let pct = 100.0 * requests as f64 / std::cmp::max(global.requests, 1) as f64;
+                format!("{}: {} ({:.1}%)", class, requests, pct)
+            })
+            .collect();
+        writeln!(out, "{}", classes.join(" "))?;
+
+        Ok(())
+    }
+
+    // One object per line:
+    // {"time":..,"late":..,"dropped":..,"all":{..},"window":{..},"group":"URI","rows":[{"key":..},..]}
+    fn print_json(out: &mut impl Write, s: &Snapshot<'_>) -> Result<(), Error> {
+        let rows: Vec<Value> = s
+            .rows
+            .iter()
+            .map(|(key, rb)| {
+                let mut row = Self::counters_json(&rb.stats.global, s.window_elapsed);
+                row["key"] = json!(key.to_string());
+                row
+            })
+            .collect();
+
+        let snapshot = json!({
+            "time": s.now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            "late": s.late,
+            "dropped": s.dropped,
+            "all": Self::stats_json(s.alltime, s.alltime_elapsed),
+            "window": Self::stats_json(s.window, s.window_elapsed),
+            "group": s.group_by.to_string(),
+            "rows": rows,
+        });
+
+        writeln!(out, "{}", snapshot)?;
+
+        Ok(())
+    }
+
+    fn stats_json(stats: &Stats, elapsed: f64) -> Value {
+        let mut value = Self::counters_json(&stats.global, elapsed);
+
+        let mut by_status = serde_json::Map::new();
+        for (i, class) in Self::STATUS_CLASSES.iter().enumerate() {
+            by_status.insert(
+                class.to_string(),
+                Self::counters_json(&stats.by_status_code[i + 1], elapsed),
+            );
+        }
+        value["status"] = Value::Object(by_status);
+
+        value
+    }
+
+    fn counters_json(counters: &Counters, elapsed: f64) -> Value {
+        let micros = |t: Option<std::time::Duration>| t.map(|t| t.as_micros() as u64);
+
+        json!({
+            "requests": counters.requests,
+            "bytes": counters.bytes,
+            "requests_per_sec": counters.requests as f64 / elapsed,
+            "bytes_per_sec": counters.bytes as f64 / elapsed,
+            "avg_time": micros(counters.avg_time()),
+            "p50_time": micros(counters.percentile_time(50.0)),
+            "p95_time": micros(counters.percentile_time(95.0)),
+            "p99_time": micros(counters.percentile_time(99.0)),
+            "max_time": micros(counters.max_time()),
+        })
+    }
+
+    // One row per stats line, with scope saying which: all or window (with
+    // the status class as key, or no key for the totals), or the grouping
+    // for table rows, eg uri,/index.html
+    fn print_csv(out: &mut impl Write, s: &Snapshot<'_>) -> Result<(), Error> {
+        let time = s.now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+
+        for (scope, stats, elapsed) in [
+            ("all", s.alltime, s.alltime_elapsed),
+            ("window", s.window, s.window_elapsed),
+        ]
+        .iter()
+        {
+            writeln!(
+                out,
+                "{}",
+                Self::csv_row(&time, scope, "", &stats.global, *elapsed)
+            )?;
+
+            for (i, class) in Self::STATUS_CLASSES.iter().enumerate() {
+                let counters = &stats.by_status_code[i + 1];
+                writeln!(
+                    out,
+                    "{}",
+                    Self::csv_row(&time, scope, class, counters, *elapsed)
+                )?;
+            }
+        }
+
+        let scope = s.group_by.to_string().to_lowercase();
+        for (key, rb) in s.rows.iter() {
+            writeln!(
+                out,
+                "{}",
+                Self::csv_row(
+                    &time,
+                    &scope,
+                    &key.to_string(),
+                    &rb.stats.global,
+                    s.window_elapsed
+                )
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn csv_header() -> &'static str {
+        "time,scope,key,requests,bytes,requests_per_sec,bytes_per_sec,avg_time,p50_time,p95_time,p99_time,max_time"
+    }
+
+    fn csv_row(time: &str, scope: &str, key: &str, counters: &Counters, elapsed: f64) -> String {
+        let micros = |t: Option<std::time::Duration>| match t {
+            Some(t) => t.as_micros().to_string(),
+            None => String::new(),
+        };
+
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{}",
+            time,
+            scope,
+            Self::csv_escape(key),
+            counters.requests,
+            counters.bytes,
+            counters.requests as f64 / elapsed,
+            counters.bytes as f64 / elapsed,
+            micros(counters.avg_time()),
+            micros(counters.percentile_time(50.0)),
+            micros(counters.percentile_time(95.0)),
+            micros(counters.percentile_time(99.0)),
+            micros(counters.max_time()),
+        )
+    }
+
+    // quote fields containing separators, quotes or newlines (RFC 4180)
+    fn csv_escape(field: &str) -> String {
+        if field.contains(&[',', '"', '\n', '\r'][..]) {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            String::from(field)
+        }
+    }
+
+    // by_status_code indices 1 to 5
+    const STATUS_CLASSES: [&'static str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::ManualClock;
+
+    use structopt::StructOpt;
+
+    // requests for /a and /b, the latest at 10:00:10
+    fn batch(args: &[&str]) -> Batch {
+        let options = Arc::new(Mutex::new(Options::from_iter(args)));
+
+        let now = chrono::DateTime::parse_from_rfc3339("2020-05-01T10:00:10Z").unwrap();
+        let clock = Arc::new(ManualClock::new(now.into()));
+
+        let alltime_stats = Arc::new(Mutex::new(Stats::new()));
+        let ring_buffer = Arc::new(Mutex::new(
+            RingBuffer::new(Arc::clone(&options), Some(0)).unwrap(),
+        ));
+
+        for (uri, status, secs) in [("/a", 200, 0), ("/b", 404, 5), ("/a", 200, 10)].iter() {
+            let mut request = Request::blank();
+            request.time = now - chrono::Duration::seconds(10 - secs);
+            request.uri = String::from(*uri);
+            request.status_code = *status;
+            request.size = 1000;
+
+            alltime_stats.lock().unwrap().add_request(&request);
+            ring_buffer.lock().unwrap().push(Arc::new(request)).unwrap();
+        }
+
+        Batch::new(options, clock, alltime_stats, ring_buffer)
+    }
+
+    #[test]
+    fn text_shows_the_top_rows() {
+        let batch = batch(&["apachetop", "--batch", "--top", "1"]);
+        let mut out = vec![];
+        batch.print(&mut out, OutputFormat::Text).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.starts_with("apachetop "));
+        assert!(out.contains("\nR:    3 reqs (0.30/sec) 2.93K (300B/sec) 1000B/req\n"));
+        assert!(out.contains("2xx: 2 (66.7%) 3xx: 0 (0.0%) 4xx: 1 (33.3%) 5xx: 0 (0.0%)\n"));
+
+        // no response times, so no time columns; and only the top row
+        let header = out.lines().find(|l| l.contains("REQS")).unwrap();
+        assert!(!header.contains("AVG"));
+        assert!(out.lines().any(|l| l.ends_with(" /a")));
+        assert!(!out.lines().any(|l| l.ends_with(" /b")));
+    }
+
+    #[test]
+    fn json_is_one_object_per_line() {
+        let batch = batch(&["apachetop", "--batch", "--output", "json"]);
+        let mut out = vec![];
+        batch.print(&mut out, OutputFormat::Json).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(out.lines().count(), 1);
+        let snapshot: Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(snapshot["time"], "2020-05-01T10:00:10Z");
+        assert_eq!(snapshot["window"]["requests"], 3);
+        assert_eq!(snapshot["window"]["status"]["4xx"]["bytes"], 1000);
+        assert_eq!(snapshot["group"], "URI");
+        assert_eq!(snapshot["rows"][0]["key"], "/a");
+        assert_eq!(snapshot["rows"][0]["requests"], 2);
+        assert_eq!(snapshot["rows"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn csv_keys_are_quoted_when_needed() {
+        assert_eq!(Batch::csv_escape("/index.html"), "/index.html");
+        assert_eq!(Batch::csv_escape("/a,b"), "\"/a,b\"");
+        assert_eq!(Batch::csv_escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn csv_rows_match_the_header() {
+        let mut request = Request::blank();
+        request.size = 500;
+        request.response_time = Some(std::time::Duration::from_millis(20));
+
+        let mut counters = Counters::empty();
+        counters.add_request(&request);
+        counters.add_request(&request);
+
+        let row = Batch::csv_row("2020-05-01T10:00:00Z", "uri", "/a", &counters, 4.0);
+        assert_eq!(
+            row.split(',').count(),
+            Batch::csv_header().split(',').count()
+        );
+        assert!(row.starts_with("2020-05-01T10:00:00Z,uri,/a,2,1000,0.5,250,"));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::prelude::*;
+
+use chrono::{DateTime, Utc};
+
+// Where "now" comes from, for everything that ages requests or calculates
+// rates (RingBuffer::cleanup, Window and its runtime display).
+//
+// Normally this is SystemClock. ReplayClock runs in log time instead, and
+// ManualClock only moves when told to, so timed behaviour can be tested.
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    // How long the clock has been running for.
+    fn elapsed(&self) -> std::time::Duration;
+
+    // Block until the clock reaches time, before a request with that time is
+    // released. Only ReplayClock waits.
+    fn wait_until(&self, _time: DateTime<Utc>) {}
+}
+
+pub fn from_options(options: &Options) -> Arc<dyn Clock> {
+    match options.replay {
+        Some(speed) => Arc::new(ReplayClock::new(speed)),
+        None => Arc::new(SystemClock::new()),
+    }
+}
+
+// The wall clock, with elapsed() counting from startup.
+#[derive(Debug)]
+pub struct SystemClock {
+    started: std::time::Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            started: std::time::Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn elapsed(&self) -> std::time::Duration {
+        self.started.elapsed()
+    }
+}
+
+// Log time for --replay: it starts at the first replayed request's timestamp,
+// and runs speed times faster than real time.
+#[derive(Debug)]
+pub struct ReplayClock {
+    speed: f64,
+
+    // real instant the replay started, and the log time it started at
+    origin: Mutex<Option<(std::time::Instant, DateTime<Utc>)>>,
+}
+
+impl ReplayClock {
+    pub fn new(speed: f64) -> Self {
+        Self {
+            speed,
+            origin: Mutex::new(None),
+        }
+    }
+}
+
+impl Clock for ReplayClock {
+    fn now(&self) -> DateTime<Utc> {
+        match *self.origin.lock().unwrap() {
+            Some((instant, time)) => {
+                let elapsed = instant.elapsed().mul_f64(self.speed);
+                time + chrono::Duration::from_std(elapsed)
+                    .unwrap_or_else(|_| chrono::Duration::zero())
+            }
+
+            // nothing replayed yet
+            None => Utc::now(),
+        }
+    }
+
+    fn elapsed(&self) -> std::time::Duration {
+        match *self.origin.lock().unwrap() {
+            Some((instant, _)) => instant.elapsed().mul_f64(self.speed),
+            None => std::time::Duration::from_secs(0),
+        }
+    }
+
+    // The first call starts the clock at that time.
+    fn wait_until(&self, time: DateTime<Utc>) {
+        {
+            let mut origin = self.origin.lock().unwrap();
+            if origin.is_none() {
+                *origin = Some((std::time::Instant::now(), time));
+                return;
+            }
+        }
+
+        // negative (ie the request is late) fails to_std, so doesn't wait
+        if let Ok(ahead) = (time - self.now()).to_std() {
+            thread::sleep(ahead.div_f64(self.speed));
+        }
+    }
+}
+
+// A clock which stands still until set() or advance() is called, for tests.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ManualClock {
+    started: DateTime<Utc>,
+    now: Mutex<DateTime<Utc>>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(started: DateTime<Utc>) -> Self {
+        Self {
+            started,
+            now: Mutex::new(started),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: chrono::Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = *now + by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+
+    fn elapsed(&self) -> std::time::Duration {
+        (self.now() - self.started)
+            .to_std()
+            .unwrap_or_else(|_| std::time::Duration::from_secs(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().into()
+    }
+
+    #[test]
+    fn manual_clock_only_moves_when_told() {
+        let start = at("2020-05-01T10:00:00Z");
+        let clock = ManualClock::new(start);
+
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.elapsed().as_secs(), 0);
+
+        clock.advance(chrono::Duration::seconds(90));
+        assert_eq!(clock.now(), at("2020-05-01T10:01:30Z"));
+        assert_eq!(clock.elapsed().as_secs(), 90);
+
+        clock.set(at("2020-05-01T11:00:00Z"));
+        assert_eq!(clock.elapsed().as_secs(), 3600);
+    }
+
+    #[test]
+    fn replay_clock_starts_at_first_request() {
+        let start = at("2020-05-01T10:00:00Z");
+        let clock = ReplayClock::new(1000.0);
+
+        assert_eq!(clock.elapsed().as_secs(), 0);
+
+        clock.wait_until(start);
+        assert!(clock.now() >= start);
+
+        // 5s of log time at 1000x is 5ms of real time
+        clock.wait_until(start + chrono::Duration::seconds(5));
+        assert!(clock.now() >= start + chrono::Duration::seconds(5));
+        assert!(clock.elapsed().as_secs() >= 5);
+    }
+}
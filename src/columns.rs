@@ -0,0 +1,286 @@
+use crate::prelude::*;
+
+// A column of the grouped table, chosen with --columns or the c key, and
+// usable as the --order. Each is named as its header, in lowercase.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Column {
+    Requests,
+    RequestRate,
+    Size,
+    SizeRate,
+    SizePerRequest,
+    ErrorRate,
+    ClientErrors,
+    ServerErrors,
+    UniqueIps,
+    Age,
+    AvgTime,
+    P50Time,
+    P95Time,
+    P99Time,
+    MaxTime,
+}
+
+impl Column {
+    pub const ALL: [Column; 15] = [
+        Self::Requests,
+        Self::RequestRate,
+        Self::Size,
+        Self::SizeRate,
+        Self::SizePerRequest,
+        Self::ErrorRate,
+        Self::ClientErrors,
+        Self::ServerErrors,
+        Self::UniqueIps,
+        Self::Age,
+        Self::AvgTime,
+        Self::P50Time,
+        Self::P95Time,
+        Self::P99Time,
+        Self::MaxTime,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Requests => "reqs",
+            Self::RequestRate => "reqs/s",
+            Self::Size => "size",
+            Self::SizeRate => "sz/s",
+            Self::SizePerRequest => "sz/req",
+            Self::ErrorRate => "err%",
+            Self::ClientErrors => "4xx",
+            Self::ServerErrors => "5xx",
+            Self::UniqueIps => "ips",
+            Self::Age => "age",
+            Self::AvgTime => "avg",
+            Self::P50Time => "p50",
+            Self::P95Time => "p95",
+            Self::P99Time => "p99",
+            Self::MaxTime => "max",
+        }
+    }
+
+    // headers and values are right aligned to this
+    pub fn width(self) -> usize {
+        match self {
+            // see Window::humansize
+            Self::Size | Self::SizeRate | Self::SizePerRequest => 7,
+            _ => 6,
+        }
+    }
+
+    // response time columns, which are left out when the log has no times
+    pub fn is_time(self) -> bool {
+        matches!(
+            self,
+            Self::AvgTime | Self::P50Time | Self::P95Time | Self::P99Time | Self::MaxTime
+        )
+    }
+
+    // The chosen columns that are worth showing for these stats: without
+    // any response times, the time columns would only be dashes.
+    pub fn shown(columns: &[Column], stats: &Stats) -> Vec<Column> {
+        let timed = stats.global.timed_requests > 0;
+        columns
+            .iter()
+            .filter(|column| timed || !column.is_time())
+            .copied()
+            .collect()
+    }
+
+    pub fn header(self) -> String {
+        format!(
+            "{:>width$}",
+            self.name().to_uppercase(),
+            width = self.width()
+        )
+    }
+
+    // This column for one row. elapsed is for the per-second rates, and now
+    // for the age of the last request.
+    pub fn value(
+        self,
+        rb: &RingBuffer,
+        elapsed: f64,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> String {
+        let global = &rb.stats.global;
+        let reqs_non_zero = std::cmp::max(global.requests, 1) as f64;
+
+        let value = match self {
+            Self::Requests => global.requests.to_string(),
+            Self::RequestRate => format!("{:.2}", global.requests as f64 / elapsed),
+            Self::Size => Window::humansize(global.bytes as f64),
+            Self::SizeRate => Window::humansize(global.bytes as f64 / elapsed),
+            Self::SizePerRequest => Window::humansize(global.bytes as f64 / reqs_non_zero),
+            Self::ErrorRate => format!("{:.1}", 100.0 * Self::errors(rb) as f64 / reqs_non_zero),
+            Self::ClientErrors => rb.stats.by_status_code[4].requests.to_string(),
+            Self::ServerErrors => rb.stats.by_status_code[5].requests.to_string(),
+            Self::UniqueIps => rb.unique_ips().to_string(),
+            Self::Age => match rb.buffer.back() {
+                Some(last) => Self::age(
+                    (now - chrono::DateTime::<chrono::Utc>::from(last.time)).num_seconds(),
+                ),
+                None => String::from("-"),
+            },
+            Self::AvgTime => Window::humantime(global.avg_time()),
+            Self::P50Time => Window::humantime(global.percentile_time(50.0)),
+            Self::P95Time => Window::humantime(global.percentile_time(95.0)),
+            Self::P99Time => Window::humantime(global.percentile_time(99.0)),
+            Self::MaxTime => Window::humantime(global.max_time()),
+        };
+
+        format!("{:>width$}", value, width = self.width())
+    }
+
+    // What rows are ordered by when sorting on this column, see impl Ord
+    // for RingBuffer. None (eg no times) sorts last, whichever the direction.
+    // Rates order the same as their totals, as every row in a table shares
+    // the same elapsed time.
+    pub fn sort_value(self, rb: &RingBuffer) -> Option<f64> {
+        let global = &rb.stats.global;
+        let reqs_non_zero = std::cmp::max(global.requests, 1) as f64;
+        let secs = |t: Option<std::time::Duration>| t.map(|t| t.as_secs_f64());
+
+        match self {
+            Self::Requests | Self::RequestRate => Some(global.requests as f64),
+            Self::Size | Self::SizeRate => Some(global.bytes as f64),
+            Self::SizePerRequest => Some(global.bytes as f64 / reqs_non_zero),
+            Self::ErrorRate => Some(Self::errors(rb) as f64 / reqs_non_zero),
+            Self::ClientErrors => Some(rb.stats.by_status_code[4].requests as f64),
+            Self::ServerErrors => Some(rb.stats.by_status_code[5].requests as f64),
+            Self::UniqueIps => Some(rb.unique_ips() as f64),
+            // the earlier the last request, the older
+            Self::Age => rb
+                .buffer
+                .back()
+                .map(|last| -(last.time.timestamp_millis() as f64)),
+            Self::AvgTime => secs(global.avg_time()),
+            Self::P50Time => secs(global.percentile_time(50.0)),
+            Self::P95Time => secs(global.percentile_time(95.0)),
+            Self::P99Time => secs(global.percentile_time(99.0)),
+            Self::MaxTime => secs(global.max_time()),
+        }
+    }
+
+    // 4xx and 5xx responses
+    fn errors(rb: &RingBuffer) -> i64 {
+        rb.stats.by_status_code[4].requests + rb.stats.by_status_code[5].requests
+    }
+
+    // eg 45s, 12m, 3h, 2d
+    fn age(seconds: i64) -> String {
+        let seconds = std::cmp::max(seconds, 0);
+        match seconds {
+            0..=59 => format!("{}s", seconds),
+            60..=3599 => format!("{}m", seconds / 60),
+            3600..=86399 => format!("{}h", seconds / 3600),
+            _ => format!("{}d", seconds / 86400),
+        }
+    }
+}
+
+impl std::str::FromStr for Column {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<Self, <Self as std::str::FromStr>::Err> {
+        let input = input.trim().to_lowercase();
+
+        Self::ALL
+            .iter()
+            .find(|column| column.name() == input)
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<&str> = Self::ALL.iter().map(|c| c.name()).collect();
+                anyhow!("unknown column {} (can be {})", input, names.join(", "))
+            })
+    }
+}
+
+impl std::fmt::Display for Column {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use structopt::StructOpt;
+
+    #[test]
+    fn names_round_trip() {
+        for column in Column::ALL.iter() {
+            assert_eq!(column.to_string().parse::<Column>().unwrap(), *column);
+            assert!(column.header().len() <= column.width());
+        }
+
+        assert_eq!("P95".parse::<Column>().unwrap(), Column::P95Time);
+        assert!("colour".parse::<Column>().is_err());
+    }
+
+    #[test]
+    fn error_counts_ips_and_age() {
+        let options = Options::from_iter(&["apachetop", "--size", "100"]);
+        let mut rb = RingBuffer::new(Arc::new(Mutex::new(options)), None).unwrap();
+
+        let time = chrono::DateTime::parse_from_rfc3339("2020-05-01T10:00:00Z").unwrap();
+        for (ip, status) in [
+            ("10.0.0.1", 200),
+            ("10.0.0.2", 404),
+            ("10.0.0.1", 503),
+            ("10.0.0.1", 200),
+        ]
+        .iter()
+        {
+            let mut request = Request::blank();
+            request.ip_address = ip.parse().unwrap();
+            request.status_code = *status;
+            request.time = time;
+            rb.push(Arc::new(request)).unwrap();
+        }
+
+        let now = (time + chrono::Duration::seconds(150)).into();
+        let value = |rb: &RingBuffer, column: Column| column.value(rb, 1.0, now).trim().to_string();
+
+        assert_eq!(value(&rb, Column::ErrorRate), "50.0");
+        assert_eq!(value(&rb, Column::ClientErrors), "1");
+        assert_eq!(value(&rb, Column::ServerErrors), "1");
+        assert_eq!(value(&rb, Column::UniqueIps), "2");
+        assert_eq!(value(&rb, Column::Age), "2m");
+        assert_eq!(value(&rb, Column::AvgTime), "-");
+
+        // the count of addresses follows the buffer
+        rb.pop();
+        rb.pop();
+        assert_eq!(value(&rb, Column::UniqueIps), "1");
+        assert_eq!(Column::AvgTime.sort_value(&rb), None);
+    }
+
+    #[test]
+    fn sorting_cycles_through_shown_columns() {
+        let mut options = Options::from_iter(&["apachetop", "--columns", "reqs,avg,size"]);
+        let mut rb = RingBuffer::new(
+            Arc::new(Mutex::new(Options::from_iter(&["apachetop"]))),
+            None,
+        )
+        .unwrap();
+        rb.push(Arc::new(Request::blank())).unwrap();
+
+        // no response times, so avg isn't shown, or sorted by
+        let shown = Column::shown(&options.columns, &rb.stats);
+        let mut sorted = vec![];
+        for _ in 0..3 {
+            options.toggle_sort(&shown);
+            sorted.push(options.order.column);
+        }
+        assert_eq!(sorted, [Column::Size, Column::Requests, Column::Size]);
+
+        let mut request = Request::blank();
+        request.response_time = Some(std::time::Duration::from_millis(5));
+        rb.push(Arc::new(request)).unwrap();
+        options.toggle_sort(&Column::shown(&options.columns, &rb.stats));
+        options.toggle_sort(&Column::shown(&options.columns, &rb.stats));
+        assert_eq!(options.order.column, Column::AvgTime);
+    }
+}
@@ -0,0 +1,451 @@
+use crate::prelude::*;
+
+use regex::Regex;
+
+use std::borrow::Cow;
+
+// A filter expression from --filter or the f key, deciding which requests
+// appear in the grouped view, eg
+//
+//   status>=500 && uri~"^/api" && ip!=10.0.0.5
+//
+// Comparisons are joined with && and ||, negated with !, and grouped with
+// parentheses; && binds tighter than ||. Values are numbers, bare words, or
+// double quoted strings with \" and \\ escapes.
+//
+// Numeric fields (status, size, time in milliseconds) take == != < <= > >=.
+// Text fields (ip, method, uri, referer, agent, username) take == and !=,
+// or ~ and !~ for a regex match.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    // as entered, for display and editing
+    source: String,
+
+    expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Field, Op, Value),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Field {
+    Ip,
+    Method,
+    Uri,
+    Status,
+    Size,
+    Time,
+    Referer,
+    UserAgent,
+    Username,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Match,
+    NotMatch,
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Number(f64),
+    Text(String),
+    Regex(Regex),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl Filter {
+    pub fn matches(&self, request: &Request) -> bool {
+        self.expr.matches(request)
+    }
+
+    fn tokenize(input: &str) -> Result<Vec<Token>, Error> {
+        let mut tokens = vec![];
+        let mut chars = input.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            let token = match c {
+                ' ' | '\t' => continue,
+                '(' => Token::Open,
+                ')' => Token::Close,
+                '~' => Token::Op(Op::Match),
+                '&' | '|' => {
+                    if chars.next() != Some(c) {
+                        return Err(anyhow!("expected {}{} in filter", c, c));
+                    }
+                    if c == '&' {
+                        Token::And
+                    } else {
+                        Token::Or
+                    }
+                }
+                '=' => {
+                    // = and == are the same
+                    chars.next_if_eq(&'=');
+                    Token::Op(Op::Eq)
+                }
+                '!' => match chars.peek() {
+                    Some('=') => {
+                        chars.next();
+                        Token::Op(Op::Ne)
+                    }
+                    Some('~') => {
+                        chars.next();
+                        Token::Op(Op::NotMatch)
+                    }
+                    _ => Token::Not,
+                },
+                '<' | '>' => {
+                    let or_equal = chars.next_if_eq(&'=').is_some();
+                    Token::Op(match (c, or_equal) {
+                        ('<', false) => Op::Lt,
+                        ('<', true) => Op::Le,
+                        ('>', false) => Op::Gt,
+                        _ => Op::Ge,
+                    })
+                }
+                '"' => {
+                    let mut s = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => match chars.next() {
+                                Some(c @ '"') | Some(c @ '\\') => s.push(c),
+                                // leave other escapes for the regex, eg \.
+                                Some(c) => {
+                                    s.push('\\');
+                                    s.push(c);
+                                }
+                                None => return Err(anyhow!("unterminated string in filter")),
+                            },
+                            Some(c) => s.push(c),
+                            None => return Err(anyhow!("unterminated string in filter")),
+                        }
+                    }
+                    Token::Quoted(s)
+                }
+                c => {
+                    let mut s = String::from(c);
+                    while let Some(c) = chars.next_if(|c| !"()~&|=!<>\" \t".contains(*c)) {
+                        s.push(c);
+                    }
+                    Token::Word(s)
+                }
+            };
+
+            tokens.push(token);
+        }
+
+        Ok(tokens)
+    }
+}
+
+impl Expr {
+    fn matches(&self, request: &Request) -> bool {
+        match self {
+            Self::And(a, b) => a.matches(request) && b.matches(request),
+            Self::Or(a, b) => a.matches(request) || b.matches(request),
+            Self::Not(a) => !a.matches(request),
+            Self::Compare(field, op, Value::Number(n)) => match field.number(request) {
+                Some(x) => match op {
+                    Op::Eq => (x - n).abs() < f64::EPSILON,
+                    Op::Ne => (x - n).abs() >= f64::EPSILON,
+                    Op::Lt => x < *n,
+                    Op::Le => x <= *n,
+                    Op::Gt => x > *n,
+                    Op::Ge => x >= *n,
+                    Op::Match | Op::NotMatch => false,
+                },
+                // eg time, when the log format doesn't record it
+                None => false,
+            },
+            Self::Compare(field, op, Value::Text(s)) => {
+                let equal = field.text(request) == *s;
+                if *op == Op::Eq {
+                    equal
+                } else {
+                    !equal
+                }
+            }
+            Self::Compare(field, op, Value::Regex(re)) => {
+                let found = re.is_match(&field.text(request));
+                if *op == Op::Match {
+                    found
+                } else {
+                    !found
+                }
+            }
+        }
+    }
+}
+
+impl Field {
+    fn from_name(name: &str) -> Result<Self, Error> {
+        Ok(match name {
+            "ip" => Self::Ip,
+            "method" => Self::Method,
+            "uri" => Self::Uri,
+            "status" => Self::Status,
+            "size" => Self::Size,
+            "time" => Self::Time,
+            "referer" | "referrer" => Self::Referer,
+            "agent" | "ua" | "user_agent" => Self::UserAgent,
+            "user" | "username" => Self::Username,
+            x => return Err(anyhow!("unknown field {}", x)),
+        })
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(self, Self::Status | Self::Size | Self::Time)
+    }
+
+    fn number(self, request: &Request) -> Option<f64> {
+        match self {
+            Self::Status => Some(request.status_code as f64),
+            Self::Size => Some(request.size as f64),
+            Self::Time => request.response_time.map(|t| t.as_secs_f64() * 1000.0),
+            _ => None,
+        }
+    }
+
+    fn text(self, request: &Request) -> Cow<'_, str> {
+        match self {
+            Self::Ip => Cow::Owned(request.ip_address.to_string()),
+            Self::Method => Cow::Owned(format!("{:?}", request.method)),
+            Self::Uri => Cow::Borrowed(&request.uri),
+            Self::Referer => Cow::Borrowed(&request.referer),
+            Self::UserAgent => Cow::Borrowed(&request.user_agent),
+            Self::Username => Cow::Borrowed(request.username.as_deref().unwrap_or_default()),
+            // numeric fields are never compared as text, see Parser::compare
+            _ => Cow::Borrowed(""),
+        }
+    }
+}
+
+impl std::str::FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, <Self as std::str::FromStr>::Err> {
+        let tokens = Self::tokenize(input)?;
+        let mut parser = Parser { tokens, pos: 0 };
+
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(anyhow!("unexpected {:?} in filter", token));
+        }
+
+        Ok(Self {
+            source: input.trim().to_string(),
+            expr,
+        })
+    }
+}
+
+impl std::fmt::Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+// Recursive descent over the tokens, one function per precedence level.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn next_if(&mut self, token: &Token) -> bool {
+        if self.tokens.get(self.pos) == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    // a || b
+    fn or(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.and()?;
+        while self.next_if(&Token::Or) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    // a && b
+    fn and(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.unary()?;
+        while self.next_if(&Token::And) {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    // !a, (a), or a comparison
+    fn unary(&mut self) -> Result<Expr, Error> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                if !self.next_if(&Token::Close) {
+                    return Err(anyhow!("missing ) in filter"));
+                }
+                Ok(expr)
+            }
+            Some(Token::Word(name)) => self.compare(&name),
+            Some(token) => Err(anyhow!("expected a field name, found {:?}", token)),
+            None => Err(anyhow!("filter ends too early")),
+        }
+    }
+
+    // field op value
+    fn compare(&mut self, name: &str) -> Result<Expr, Error> {
+        let field = Field::from_name(name)?;
+
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            _ => return Err(anyhow!("expected a comparison after {}", name)),
+        };
+
+        let value = match self.next() {
+            Some(Token::Word(s)) | Some(Token::Quoted(s)) => s,
+            _ => return Err(anyhow!("expected a value to compare {} with", name)),
+        };
+
+        let value = match op {
+            Op::Match | Op::NotMatch if field.is_numeric() => {
+                return Err(anyhow!("{} is a number, so can't be matched with ~", name));
+            }
+            Op::Match | Op::NotMatch => {
+                Value::Regex(Regex::new(&value).with_context(|| format!("bad regex for {}", name))?)
+            }
+            _ if field.is_numeric() => Value::Number(
+                value
+                    .parse()
+                    .map_err(|_| anyhow!("expected a number for {}, found {}", name, value))?,
+            ),
+            Op::Eq | Op::Ne => Value::Text(match field {
+                // written the way Display writes them, eg for IPv6
+                Field::Ip => value
+                    .parse::<IpAddr>()
+                    .with_context(|| format!("bad ip address {}", value))?
+                    .to_string(),
+                Field::Method => value.to_uppercase(),
+                _ => value,
+            }),
+            _ => return Err(anyhow!("{} can only be compared with ==, != or ~", name)),
+        };
+
+        Ok(Expr::Compare(field, op, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(ip: &str, status: i64, uri: &str) -> Request {
+        let mut request = Request::blank();
+        request.ip_address = ip.parse().unwrap();
+        request.status_code = status;
+        request.uri = String::from(uri);
+        request
+    }
+
+    fn filter(input: &str) -> Filter {
+        input.parse().unwrap()
+    }
+
+    #[test]
+    fn combines_comparisons() {
+        let f = filter(r#"status>=500 && uri~"^/api" && ip!=10.0.0.5"#);
+
+        assert!(f.matches(&request("10.0.0.1", 503, "/api/users")));
+        assert!(!f.matches(&request("10.0.0.5", 503, "/api/users")));
+        assert!(!f.matches(&request("10.0.0.1", 200, "/api/users")));
+        assert!(!f.matches(&request("10.0.0.1", 500, "/index.html")));
+        assert_eq!(
+            f.to_string(),
+            r#"status>=500 && uri~"^/api" && ip!=10.0.0.5"#
+        );
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let f = filter("status==404 || status==200 && uri==/a");
+        assert!(f.matches(&request("10.0.0.1", 404, "/b")));
+        assert!(f.matches(&request("10.0.0.1", 200, "/a")));
+        assert!(!f.matches(&request("10.0.0.1", 200, "/b")));
+
+        let f = filter("(status==404 || status==200) && !(uri==/a)");
+        assert!(f.matches(&request("10.0.0.1", 404, "/b")));
+        assert!(!f.matches(&request("10.0.0.1", 200, "/a")));
+    }
+
+    #[test]
+    fn method_and_missing_times() {
+        let mut post = request("::1", 201, "/api");
+        post.method = crate::request::HttpMethod::POST;
+
+        assert!(filter("method==post").matches(&post));
+        assert!(filter("ip=0:0::1").matches(&post));
+
+        // no response time logged, so no time comparison is true
+        assert!(!filter("time<100").matches(&post));
+        assert!(!filter("time>=100").matches(&post));
+
+        post.response_time = Some(std::time::Duration::from_millis(50));
+        assert!(filter("time<100").matches(&post));
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        for input in [
+            "",
+            "status",
+            "status>=",
+            "status>=abc",
+            "status~5",
+            "uri>/a",
+            "colour==red",
+            "ip==nonsense",
+            "uri~\"(\"",
+            "(status==200",
+            "status==200 &",
+            "status==200 uri==/a",
+            "uri==\"/a",
+        ]
+        .iter()
+        {
+            assert!(input.parse::<Filter>().is_err(), "{} parsed", input);
+        }
+    }
+}
@@ -6,35 +6,58 @@ pub struct GroupedStats {
 
     group_by: GroupBy,
 
+    // level in the --group chain for each bucket's own grouping, if any;
+    // see RingBuffer::new
+    child_level: Option<usize>,
+
     buffer: HashMap<GroupKey, RingBuffer>,
+
+    // see memory(); kept up to date by add and remove, so it's cheap to read
+    memory: usize,
 }
 
 impl GroupedStats {
-    pub fn new(options: Arc<Mutex<Options>>, group_by: GroupBy) -> Self {
+    pub fn new(
+        options: Arc<Mutex<Options>>,
+        group_by: GroupBy,
+        child_level: Option<usize>,
+    ) -> Self {
         let buffer = HashMap::new();
 
         Self {
             options,
             group_by,
+            child_level,
             buffer,
+            memory: 0,
         }
     }
 
     // set a new group_by key, which means the previous buffer is now invalid, so clear it.
     pub fn group_by(&mut self, group_by: GroupBy) {
-        self.buffer.clear();
+        self.clear();
         self.group_by = group_by;
     }
 
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.memory = 0;
+    }
+
     pub fn add(&mut self, request: Arc<Request>) -> Result<(), Error> {
         let key = request.group_key(self.group_by);
 
         match self.buffer.get_mut(&key) {
-            Some(bucket) => bucket.push(request)?,
+            Some(bucket) => {
+                let before = bucket.memory();
+                bucket.push(request)?;
+                self.memory = self.memory - before + bucket.memory();
+            }
             None => {
                 // nest a new RingBuffer inside
-                let mut bucket = RingBuffer::new(Arc::clone(&self.options), false)?;
+                let mut bucket = RingBuffer::new(Arc::clone(&self.options), self.child_level)?;
                 bucket.push(request)?;
+                self.memory += Self::entry_memory(&key, &bucket);
                 self.buffer.insert(key, bucket);
             }
         }
@@ -43,17 +66,37 @@ impl GroupedStats {
     }
 
     pub fn remove(&mut self, request: Arc<Request>) {
-        // if the first request in any hash value matches, pop it
-        for v in self.buffer.values_mut() {
-            if let Some(r) = v.buffer.front() {
-                if r == &request {
-                    v.pop();
-                }
+        let key = request.group_key(self.group_by);
+
+        if let Some(bucket) = self.buffer.get_mut(&key) {
+            self.memory -= Self::entry_memory(&key, bucket);
+            bucket.remove(&request);
+
+            // clean out hash entries which have no requests left
+            if bucket.buffer.is_empty() {
+                self.buffer.remove(&key);
+            } else {
+                self.memory += Self::entry_memory(&key, bucket);
             }
         }
+    }
+
+    // Rough heap usage of the buckets and everything nested in them.
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
 
-        // clean out hash entries which have no requests left
-        self.buffer.retain(|_, v| !v.buffer.is_empty());
+    fn entry_memory(key: &GroupKey, bucket: &RingBuffer) -> usize {
+        std::mem::size_of::<(GroupKey, RingBuffer)>() + key.memory() + bucket.memory()
+    }
+
+    pub fn get(&self, key: &GroupKey) -> Option<&RingBuffer> {
+        self.buffer.get(key)
+    }
+
+    // what the buckets are keyed by
+    pub fn grouping(&self) -> GroupBy {
+        self.group_by
     }
 
     pub fn iter(&self) -> std::collections::hash_map::Iter<'_, GroupKey, RingBuffer> {
@@ -61,7 +104,8 @@ impl GroupedStats {
     }
 }
 
-#[derive(Eq, PartialEq, Debug, Hash, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Hash, Clone)]
 pub enum GroupKey {
     IpAddress(IpAddr),
     Referer(String),
@@ -70,6 +114,16 @@ pub enum GroupKey {
     Username(String),
 }
 
+impl GroupKey {
+    // heap used by the key, beyond its inline size
+    fn memory(&self) -> usize {
+        match self {
+            Self::Referer(s) | Self::URI(s) | Self::Username(s) => s.capacity(),
+            Self::IpAddress(_) | Self::StatusCode(_) => 0,
+        }
+    }
+}
+
 impl std::fmt::Display for GroupKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if let Some(width) = f.width() {
@@ -0,0 +1,227 @@
+// Log-bucketed histogram of response times (in microseconds).
+//
+// Each power of two is split into SUB_BUCKETS linear buckets, so any value is
+// recorded with under 1% error. Bucket boundaries are fixed, which means
+// values can be removed again (as RingBuffer evicts requests), and two
+// histograms can be combined by adding their counts (see impl AddAssign).
+//
+// Buckets are stored sparsely, since most groups only ever see a handful of
+// distinct response times.
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct Histogram {
+    counts: std::collections::BTreeMap<usize, Bucket>,
+    total: u64,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+struct Bucket {
+    count: u64,
+
+    // Largest value recorded in the bucket, so max() is exact rather than
+    // the top of the bucket's range. It isn't lowered by remove() while the
+    // bucket has other values left, so can then be up to 1% high.
+    max: u64,
+}
+
+const SUB_BUCKET_BITS: u32 = 7;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, value: u64) {
+        let bucket = self
+            .counts
+            .entry(Self::index(value))
+            .or_insert(Bucket { count: 0, max: 0 });
+        bucket.count += 1;
+        bucket.max = std::cmp::max(bucket.max, value);
+        self.total += 1;
+    }
+
+    pub fn remove(&mut self, value: u64) {
+        let i = Self::index(value);
+
+        if let Some(bucket) = self.counts.get_mut(&i) {
+            bucket.count -= 1;
+            self.total -= 1;
+
+            if bucket.count == 0 {
+                self.counts.remove(&i);
+            }
+        }
+    }
+
+    pub fn min(&self) -> Option<u64> {
+        let (i, _) = self.counts.iter().next()?;
+        Some(Self::lowest_value(*i))
+    }
+
+    pub fn max(&self) -> Option<u64> {
+        let (_, bucket) = self.counts.iter().next_back()?;
+        Some(bucket.max)
+    }
+
+    // Rough heap usage, for memory accounting.
+    pub fn memory(&self) -> usize {
+        self.counts.len() * std::mem::size_of::<(usize, Bucket)>()
+    }
+
+    // The value below which pct% of recorded values fall, eg percentile(99.0).
+    pub fn percentile(&self, pct: f64) -> Option<u64> {
+        if self.total == 0 {
+            return None;
+        }
+
+        // rank of the value we want, counting from 1
+        let rank = ((pct / 100.0) * self.total as f64).ceil().max(1.0) as u64;
+
+        // the top of the bucket, but never more than was actually recorded
+        let mut seen = 0;
+        for (i, bucket) in self.counts.iter() {
+            seen += bucket.count;
+            if seen >= rank {
+                return Some(std::cmp::min(Self::highest_value(*i), bucket.max));
+            }
+        }
+
+        self.max()
+    }
+
+    // Values below SUB_BUCKETS get a bucket each; above that, the top
+    // SUB_BUCKET_BITS bits of the value (below the leading one) pick a bucket
+    // within its power of two.
+    fn index(value: u64) -> usize {
+        if value < SUB_BUCKETS {
+            return value as usize;
+        }
+
+        let magnitude = 63 - value.leading_zeros();
+        let shift = magnitude - SUB_BUCKET_BITS;
+        let sub = (value >> shift) & (SUB_BUCKETS - 1);
+
+        (((shift + 1) as u64) << SUB_BUCKET_BITS | sub) as usize
+    }
+
+    fn lowest_value(index: usize) -> u64 {
+        let index = index as u64;
+        if index < SUB_BUCKETS {
+            return index;
+        }
+
+        let shift = (index >> SUB_BUCKET_BITS) - 1;
+        let sub = index & (SUB_BUCKETS - 1);
+
+        (SUB_BUCKETS + sub) << shift
+    }
+
+    fn highest_value(index: usize) -> u64 {
+        Self::lowest_value(index + 1) - 1
+    }
+}
+
+impl std::ops::AddAssign<&Histogram> for Histogram {
+    fn add_assign(&mut self, other: &Histogram) {
+        for (i, theirs) in other.counts.iter() {
+            let ours = self.counts.entry(*i).or_insert(Bucket { count: 0, max: 0 });
+            ours.count += theirs.count;
+            ours.max = std::cmp::max(ours.max, theirs.max);
+        }
+
+        self.total += other.total;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(values: &[u64]) -> Histogram {
+        let mut h = Histogram::new();
+        for v in values {
+            h.record(*v);
+        }
+        h
+    }
+
+    #[test]
+    fn buckets_are_within_one_percent() {
+        // small values get a bucket each
+        for v in 0..SUB_BUCKETS {
+            assert_eq!(Histogram::lowest_value(Histogram::index(v)), v);
+            assert_eq!(Histogram::highest_value(Histogram::index(v)), v);
+        }
+
+        for v in [128, 129, 1000, 12_345, 1_000_000, 987_654_321, u64::MAX / 2].iter() {
+            let i = Histogram::index(*v);
+            let (low, high) = (Histogram::lowest_value(i), Histogram::highest_value(i));
+            assert!(low <= *v && *v <= high, "{} not in {}..={}", v, low, high);
+            assert!(
+                (high - low) as f64 <= *v as f64 / 100.0,
+                "bucket of {} too wide",
+                v
+            );
+            assert_eq!(Histogram::lowest_value(i + 1), high + 1);
+        }
+    }
+
+    #[test]
+    fn percentiles_and_exact_max() {
+        assert_eq!(Histogram::new().percentile(50.0), None);
+        assert_eq!(Histogram::new().max(), None);
+
+        let values: Vec<u64> = (1..=100).map(|v| v * 1000).collect();
+        let h = histogram(&values);
+
+        let near = |got: Option<u64>, want: u64| {
+            let got = got.unwrap();
+            assert!(
+                got >= want && got as f64 <= want as f64 * 1.01,
+                "{} vs {}",
+                got,
+                want
+            );
+        };
+        near(h.percentile(50.0), 50_000);
+        near(h.percentile(95.0), 95_000);
+        near(h.percentile(99.0), 99_000);
+
+        assert_eq!(h.max(), Some(100_000));
+        assert_eq!(h.percentile(100.0), Some(100_000));
+        assert_eq!(histogram(&[1001]).max(), Some(1001));
+    }
+
+    #[test]
+    fn remove_undoes_record() {
+        let mut h = histogram(&[10, 2000, 300_000]);
+        h.record(5_000_000);
+        h.remove(5_000_000);
+        assert_eq!(h, histogram(&[10, 2000, 300_000]));
+        assert_eq!(h.max(), Some(300_000));
+
+        h.remove(300_000);
+        h.remove(2000);
+        h.remove(10);
+        assert_eq!(h, Histogram::new());
+
+        // removing something never recorded is ignored
+        h.remove(42);
+        assert_eq!(h.percentile(50.0), None);
+    }
+
+    #[test]
+    fn merge_adds_counts() {
+        let mut a = histogram(&[10, 2000, 2000]);
+        let b = histogram(&[2001, 300_000]);
+
+        a += &b;
+        assert_eq!(a, histogram(&[10, 2000, 2000, 2001, 300_000]));
+        assert_eq!(a.max(), Some(300_000));
+        assert_eq!(a.percentile(20.0), Some(10));
+
+        a += &Histogram::new();
+        assert_eq!(a, histogram(&[10, 2000, 2000, 2001, 300_000]));
+    }
+}
@@ -0,0 +1,317 @@
+use crate::prelude::*;
+
+use crossbeam_channel::Sender;
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+// The set of input logfiles, built from the paths, glob patterns and
+// directories given on the commandline.
+//
+// Patterns are rescanned periodically by watch(), starting a Logfile reader
+// for each new file that matches. Readers stop by themselves when their file
+// is deleted, and are forgotten here on the next scan. Compressed files are
+// only read once, so their readers are kept around after finishing; and only
+// when named as a path, since in a glob or directory they're almost always
+// an earlier log rotated away and compressed, whose requests we've seen.
+pub struct Inputs {
+    patterns: Vec<PathBuf>,
+
+    // where to start reading files which exist at startup
+    start_from: StartFrom,
+
+    log_format: Arc<LogFormat>,
+    request_tx: Sender<Request>,
+
+    // for the --backfill cutoff, which is in replayed time when replaying
+    clock: Arc<dyn Clock>,
+
+    logfiles: HashMap<PathBuf, Logfile>,
+
+    // identities of the matching files we've read, so a logfile renamed by
+    // rotation (access.log -> access.log.1) isn't picked up again as a new
+    // file. Rebuilt on each scan, since deleted files' inodes get reused.
+    seen: HashSet<(u64, u64)>,
+}
+
+impl Inputs {
+    pub fn new(
+        patterns: Vec<PathBuf>,
+        start_from: StartFrom,
+        log_format: Arc<LogFormat>,
+        request_tx: Sender<Request>,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self, Error> {
+        let mut inputs = Self {
+            patterns,
+            start_from,
+            log_format,
+            request_tx,
+            clock,
+            logfiles: HashMap::new(),
+            seen: HashSet::new(),
+        };
+
+        inputs.scan(true)?;
+
+        Ok(inputs)
+    }
+
+    // Rescan the patterns every second in a new thread, which takes ownership
+    // of all the Logfile readers.
+    pub fn watch(mut self) -> Result<thread::JoinHandle<Result<(), Error>>, Error> {
+        let c = move || -> Result<_, _> {
+            let sleep = std::time::Duration::from_secs(1);
+
+            loop {
+                thread::sleep(sleep);
+
+                // eg a file deleted mid-scan, or a directory we can't read;
+                // try again next time rather than stop watching for good
+                if let Err(e) = self.scan(false) {
+                    warn!("failed to scan inputs: {:#}", e);
+                }
+            }
+        };
+
+        Ok(thread::Builder::new()
+            .name("input_watcher".to_string())
+            .spawn(c)?)
+    }
+
+    // At startup, files which already exist are followed from the end (or
+    // backfilled, see start_from), and any error opening them is fatal. Files
+    // that appear later are read from the start, since they're new, and
+    // errors are just logged.
+    fn scan(&mut self, startup: bool) -> Result<(), Error> {
+        self.logfiles.retain(|_, logfile| {
+            if logfile.archive {
+                return true;
+            }
+
+            if logfile.is_finished() {
+                info!("stopped reading {}", logfile.path.display());
+            }
+            !logfile.is_finished()
+        });
+
+        let mut seen = HashSet::new();
+
+        for path in self.expand(startup)? {
+            let id = std::fs::metadata(&path)
+                .ok()
+                .and_then(|m| Logfile::file_id(&m));
+
+            if let Some(id) = id {
+                seen.insert(id);
+            }
+
+            // being read already (though it may have been rotated since)
+            if self.logfiles.contains_key(&path) {
+                continue;
+            }
+
+            if let Some(id) = id {
+                if self.seen.contains(&id) {
+                    debug!("{} was already read under another name", path.display());
+                    continue;
+                }
+            }
+
+            let start_from = if startup {
+                self.start_from
+            } else {
+                StartFrom::Beginning
+            };
+
+            let request_tx = self.request_tx.clone();
+            match Logfile::new(
+                path.clone(),
+                Arc::clone(&self.log_format),
+                request_tx,
+                start_from,
+                self.clock.now(),
+            ) {
+                Ok(logfile) => {
+                    info!("reading {}", path.display());
+                    self.logfiles.insert(path, logfile);
+                }
+                Err(e) if startup => return Err(e),
+                Err(e) => error!("{:?}", e),
+            }
+        }
+
+        self.seen = seen;
+
+        Ok(())
+    }
+
+    // Turn each pattern into the files it currently refers to. Plain paths
+    // are returned as-is (they may be pipes), and must exist at startup.
+    // Globs and directories leave out compressed files, see above.
+    fn expand(&self, startup: bool) -> Result<Vec<PathBuf>, Error> {
+        let mut paths = vec![];
+
+        for pattern in self.patterns.iter() {
+            if Self::is_glob(pattern) {
+                let glob = glob::glob(&pattern.to_string_lossy())
+                    .with_context(|| format!("invalid glob pattern {}", pattern.display()))?;
+                paths.extend(
+                    glob.filter_map(Result::ok)
+                        .filter(|p| p.is_file() && !Logfile::is_archive(p)),
+                );
+            } else if pattern.is_dir() {
+                paths.extend(
+                    Self::directory_files(pattern)?
+                        .into_iter()
+                        .filter(|p| !Logfile::is_archive(p)),
+                );
+            } else if startup || pattern.exists() {
+                paths.push(pattern.clone());
+            }
+        }
+
+        Ok(paths)
+    }
+
+    fn is_glob(pattern: &Path) -> bool {
+        pattern.to_string_lossy().contains(&['*', '?', '['][..])
+    }
+
+    fn directory_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+        let mut files = vec![];
+
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("failed to read directory {}", dir.display()))?;
+
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+
+        files.sort();
+
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE: &str =
+        "10.0.0.1 - - [01/May/2020:10:00:00 +0000] \"GET /a HTTP/1.1\" 200 100 \"-\" \"x\"\n";
+
+    fn directory(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("apachetop-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn start(patterns: Vec<PathBuf>) -> (Inputs, crossbeam_channel::Receiver<Request>) {
+        let (request_tx, request_rx) = crossbeam_channel::unbounded();
+        let inputs = Inputs::new(
+            patterns,
+            StartFrom::End,
+            Arc::new(LogFormat::Clf),
+            request_tx,
+            Arc::new(crate::clock::SystemClock::new()),
+        )
+        .unwrap();
+        (inputs, request_rx)
+    }
+
+    fn reading(inputs: &Inputs) -> Vec<String> {
+        let mut names: Vec<String> = inputs
+            .logfiles
+            .keys()
+            .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn rescans_directories_for_new_files() {
+        let dir = directory("inputs-dir");
+        std::fs::write(dir.join("a.log"), LINE).unwrap();
+
+        let (mut inputs, request_rx) = start(vec![dir.clone()]);
+        assert_eq!(reading(&inputs), ["a.log"]);
+
+        // files there at startup are followed from the end, new ones read
+        // from the start
+        std::fs::write(dir.join("b.log"), LINE).unwrap();
+        inputs.scan(false).unwrap();
+        assert_eq!(reading(&inputs), ["a.log", "b.log"]);
+
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(request_rx.recv_timeout(timeout).unwrap().uri, "/a");
+        assert!(request_rx.try_recv().is_err());
+
+        // a rotated file isn't read again under its new name
+        std::fs::rename(dir.join("b.log"), dir.join("b.log.1")).unwrap();
+        inputs.scan(false).unwrap();
+        assert_eq!(reading(&inputs), ["a.log", "b.log"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_named_archives_are_read() {
+        let dir = directory("inputs-archives");
+        std::fs::write(dir.join("a.log"), "").unwrap();
+
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        std::io::Write::write_all(&mut gz, LINE.as_bytes()).unwrap();
+        std::fs::write(dir.join("a.log.1.gz"), gz.finish().unwrap()).unwrap();
+
+        // eg logrotate compressing yesterday's log
+        let (mut inputs, request_rx) = start(vec![dir.clone()]);
+        std::fs::copy(dir.join("a.log.1.gz"), dir.join("a.log.2.gz")).unwrap();
+        inputs.scan(false).unwrap();
+        assert_eq!(reading(&inputs), ["a.log"]);
+        assert!(request_rx.try_recv().is_err());
+
+        // but named, it's read to the end, and only once
+        let (mut inputs, request_rx) = start(vec![dir.join("a.log.1.gz")]);
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(request_rx.recv_timeout(timeout).unwrap().uri, "/a");
+        inputs.scan(false).unwrap();
+        assert_eq!(reading(&inputs), ["a.log.1.gz"]);
+        assert!(request_rx.recv_timeout(timeout / 10).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn globs_match_files_that_appear_later() {
+        let dir = directory("inputs-glob");
+        std::fs::write(dir.join("a.log"), "").unwrap();
+        std::fs::write(dir.join("a.txt"), "").unwrap();
+
+        let (mut inputs, _request_rx) = start(vec![dir.join("*.log")]);
+        assert_eq!(reading(&inputs), ["a.log"]);
+
+        std::fs::write(dir.join("b.log"), "").unwrap();
+        std::fs::write(dir.join("b.txt"), "").unwrap();
+        inputs.scan(false).unwrap();
+        assert_eq!(reading(&inputs), ["a.log", "b.log"]);
+
+        // missing plain paths are an error at startup only
+        let (request_tx, _) = crossbeam_channel::unbounded();
+        assert!(Inputs::new(
+            vec![dir.join("missing.log")],
+            StartFrom::End,
+            Arc::new(LogFormat::Clf),
+            request_tx,
+            Arc::new(crate::clock::SystemClock::new()),
+        )
+        .is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+use crate::prelude::*;
+
+use crate::log_format::Field;
+
+use serde_json::Value;
+
+// Parses JSON-lines access logs (Caddy, Traefik, nginx with escape=json etc)
+// by pulling each Request field out of the object at a configurable path.
+#[derive(Debug)]
+pub struct JsonFormat {
+    fields: Vec<JsonField>,
+}
+
+#[derive(Debug)]
+struct JsonField {
+    field: Field,
+    path: Vec<PathSegment>,
+
+    // multiplier to get seconds from a numeric duration, eg 1e-9 for nanoseconds
+    scale: f64,
+}
+
+#[derive(Debug)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+impl JsonFormat {
+    // Build a format from a list of mappings like "status=.status" or
+    // "uri=.request.uri". Anything not mentioned looks for a top-level key
+    // named after the field itself, eg .status or .user_agent.
+    pub fn new(mappings: &[String]) -> Result<Self, Error> {
+        let mut paths: Vec<(String, String)> = [
+            "ip",
+            "identd",
+            "username",
+            "time",
+            "request",
+            "method",
+            "uri",
+            "version",
+            "status",
+            "size",
+            "referer",
+            "user_agent",
+            "response_time",
+            "upstream_response_time",
+        ]
+        .iter()
+        .map(|name| (name.to_string(), format!(".{}", name)))
+        .collect();
+
+        for mapping in mappings {
+            let mut parts = mapping.splitn(2, '=');
+            let name = parts.next().unwrap();
+            let path = parts
+                .next()
+                .ok_or_else(|| anyhow!("json field mapping {} should be NAME=PATH", mapping))?;
+
+            paths.retain(|(n, _)| n != name);
+            paths.push((name.to_string(), path.to_string()));
+        }
+
+        let fields = paths
+            .into_iter()
+            .map(|(name, path)| JsonField::new(&name, &path))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { fields })
+    }
+
+    pub fn parse(&self, input: &str) -> Result<Request, Error> {
+        let json: Value = serde_json::from_str(input)?;
+
+        let mut request = Request::blank();
+
+        for f in self.fields.iter() {
+            if let Some(value) = f.lookup(&json) {
+                f.apply(&mut request, value)?;
+            }
+        }
+
+        Ok(request)
+    }
+}
+
+impl JsonField {
+    // name is a Request field (or anything else, to store in Request::extra);
+    // path is like .request.headers.User-Agent[0], optionally followed by a
+    // unit for numeric durations: :s (the default), :ms, :us or :ns
+    fn new(name: &str, path: &str) -> Result<Self, Error> {
+        let field = match name {
+            "ip" => Field::IpAddress,
+            "identd" => Field::Identd,
+            "username" => Field::Username,
+            "time" => Field::Time,
+            "request" => Field::RequestLine,
+            "method" => Field::Method,
+            "uri" => Field::Uri,
+            "query" => Field::QueryString,
+            "version" => Field::HttpVersion,
+            "status" => Field::StatusCode,
+            "size" => Field::Size,
+            "referer" => Field::Referer,
+            "user_agent" => Field::UserAgent,
+            "response_time" => Field::ResponseTime,
+            "upstream_response_time" => Field::UpstreamResponseTime,
+            x => Field::Extra(String::from(x)),
+        };
+
+        let (path, scale) = match path.rfind(':') {
+            Some(i) => {
+                let scale = match &path[i + 1..] {
+                    "s" => 1.0,
+                    "ms" => 1e-3,
+                    "us" => 1e-6,
+                    "ns" => 1e-9,
+                    x => return Err(anyhow!("unknown duration unit {} in {}", x, path)),
+                };
+                (&path[..i], scale)
+            }
+            None => (path, 1.0),
+        };
+
+        let mut segments = vec![];
+        for part in path.trim_start_matches('.').split('.') {
+            // split off any array indices, eg headers[0]
+            let mut pieces = part.split('[');
+            let key = pieces.next().unwrap();
+            if !key.is_empty() {
+                segments.push(PathSegment::Key(String::from(key)));
+            }
+
+            for index in pieces {
+                let index = index
+                    .trim_end_matches(']')
+                    .parse::<usize>()
+                    .with_context(|| format!("invalid array index in json path {}", path))?;
+                segments.push(PathSegment::Index(index));
+            }
+        }
+
+        if segments.is_empty() {
+            return Err(anyhow!("empty json path for field {}", name));
+        }
+
+        Ok(Self {
+            field,
+            path: segments,
+            scale,
+        })
+    }
+
+    fn lookup<'a>(&self, json: &'a Value) -> Option<&'a Value> {
+        let mut value = json;
+
+        for segment in self.path.iter() {
+            value = match segment {
+                PathSegment::Key(k) => value.get(k)?,
+                PathSegment::Index(i) => value.get(i)?,
+            };
+        }
+
+        // headers are often logged as arrays of values; use the first, so
+        // long as it's a value rather than more structure
+        let value = match value {
+            Value::Array(a) => a.first()?,
+            v => v,
+        };
+
+        match value {
+            Value::Null | Value::Object(_) | Value::Array(_) => None,
+            v => Some(v),
+        }
+    }
+
+    fn apply(&self, request: &mut Request, value: &Value) -> Result<(), Error> {
+        match (&self.field, value) {
+            // numeric times are unix timestamps, possibly fractional
+            (Field::Time, Value::Number(n)) => Field::TimeMsec.apply(request, &n.to_string()),
+
+            // string times may be RFC3339 (most JSON loggers) or CLF style
+            (Field::Time, Value::String(s)) => Field::TimeIso8601
+                .apply(request, s)
+                .or_else(|_| Field::Time.apply(request, s)),
+
+            (Field::ResponseTime, Value::Number(n))
+            | (Field::UpstreamResponseTime, Value::Number(n)) => {
+                let seconds = n.as_f64().unwrap_or_default() * self.scale;
+                self.field.apply(request, &seconds.to_string())
+            }
+
+            (field, Value::String(s)) => field.apply(request, s),
+            (field, v) => field.apply(request, &v.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::request::HttpMethod;
+
+    use std::time::Duration;
+
+    fn format(mappings: &[&str]) -> JsonFormat {
+        let mappings: Vec<String> = mappings.iter().map(|m| m.to_string()).collect();
+        JsonFormat::new(&mappings).unwrap()
+    }
+
+    #[test]
+    fn reads_top_level_fields_by_default() {
+        let r = format(&[])
+            .parse(
+                r#"{"ip": "10.0.0.1", "time": "2020-05-01T10:00:00Z", "method": "POST",
+                    "uri": "/login", "status": 302, "size": 512, "response_time": 0.25}"#,
+            )
+            .unwrap();
+
+        assert_eq!(r.ip_address, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(r.time.to_rfc3339(), "2020-05-01T10:00:00+00:00");
+        assert_eq!(r.method, HttpMethod::POST);
+        assert_eq!(r.uri, "/login");
+        assert_eq!(r.status_code, 302);
+        assert_eq!(r.size, 512);
+        assert_eq!(r.response_time, Some(Duration::from_millis(250)));
+
+        assert!(format(&[]).parse("not json").is_err());
+    }
+
+    #[test]
+    fn follows_nested_paths_and_units() {
+        let f = format(&[
+            "ip=.request.remote_ip",
+            "uri=.request.uri",
+            "user_agent=.request.headers.User-Agent",
+            "response_time=.duration:ms",
+            "host=.request.hosts[1]",
+        ]);
+        let r = f
+            .parse(
+                r#"{"request": {"remote_ip": "10.0.0.2", "uri": "/a",
+                    "headers": {"User-Agent": ["curl/7.68.0", "other"]},
+                    "hosts": ["a.example", "b.example"]},
+                    "duration": 12, "status": 200}"#,
+            )
+            .unwrap();
+
+        assert_eq!(r.ip_address, "10.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(r.uri, "/a");
+        assert_eq!(r.user_agent, "curl/7.68.0");
+        assert_eq!(r.response_time, Some(Duration::from_millis(12)));
+        assert_eq!(r.extra.get("host").map(String::as_str), Some("b.example"));
+
+        assert!(JsonFormat::new(&[String::from("uri")]).is_err());
+        assert!(JsonFormat::new(&[String::from("response_time=.t:h")]).is_err());
+    }
+
+    #[test]
+    fn arrays_of_structure_are_missing_fields() {
+        let f = format(&["user_agent=.headers.User-Agent"]);
+        let r = f
+            .parse(r#"{"status": 200, "headers": {"User-Agent": [{"value": "curl"}]}}"#)
+            .unwrap();
+        assert_eq!(r.user_agent, "");
+
+        let r = f
+            .parse(r#"{"status": 200, "headers": {"User-Agent": []}}"#)
+            .unwrap();
+        assert_eq!(r.user_agent, "");
+
+        // a bad duration is an unparseable line, not a panic
+        assert!(format(&[]).parse(r#"{"response_time": -1}"#).is_err());
+    }
+
+    #[test]
+    fn out_of_range_times_are_errors() {
+        let f = format(&[]);
+        let r = f.parse(r#"{"time": 1588327200.5, "status": 200}"#).unwrap();
+        assert_eq!(r.time.to_rfc3339(), "2020-05-01T10:00:00.500+00:00");
+
+        assert!(f.parse(r#"{"time": 1e300, "status": 200}"#).is_err());
+        assert!(f.parse(r#"{"time": -1e300, "status": 200}"#).is_err());
+    }
+}
@@ -0,0 +1,229 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+// Everything a key can do in the live display. Window::act does it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Action {
+    Quit,
+    Help,
+    ToggleSort,
+    ReverseSort,
+    Columns,
+    ToggleGroup,
+    Filter,
+    Search,
+    NextMatch,
+    PreviousMatch,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Top,
+    Bottom,
+    Open,
+    Back,
+}
+
+// A key press. Shift isn't recorded, as it's already in the character.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Key {
+    code: KeyCode,
+    ctrl: bool,
+}
+
+pub struct Binding {
+    pub keys: &'static [Key],
+    pub action: Action,
+
+    // for the help overlay
+    pub description: &'static str,
+}
+
+const fn key(code: KeyCode) -> Key {
+    Key { code, ctrl: false }
+}
+
+const fn char(c: char) -> Key {
+    key(KeyCode::Char(c))
+}
+
+const fn ctrl(c: char) -> Key {
+    Key {
+        code: KeyCode::Char(c),
+        ctrl: true,
+    }
+}
+
+// Every key binding, in the order shown by the help overlay.
+pub const BINDINGS: &[Binding] = &[
+    Binding {
+        keys: &[char('q'), ctrl('c')],
+        action: Action::Quit,
+        description: "quit",
+    },
+    Binding {
+        keys: &[char('?'), char('h')],
+        action: Action::Help,
+        description: "show or hide this help",
+    },
+    Binding {
+        keys: &[char('o')],
+        action: Action::ToggleSort,
+        description: "sort by the next column",
+    },
+    Binding {
+        keys: &[char('O')],
+        action: Action::ReverseSort,
+        description: "reverse the sort order",
+    },
+    Binding {
+        keys: &[char('c')],
+        action: Action::Columns,
+        description: "choose the columns",
+    },
+    Binding {
+        keys: &[char('g')],
+        action: Action::ToggleGroup,
+        description: "next grouping",
+    },
+    Binding {
+        keys: &[char('f')],
+        action: Action::Filter,
+        description: "filter rows, eg status>=500",
+    },
+    Binding {
+        keys: &[char('/')],
+        action: Action::Search,
+        description: "search for a row",
+    },
+    Binding {
+        keys: &[char('n')],
+        action: Action::NextMatch,
+        description: "next search match",
+    },
+    Binding {
+        keys: &[char('N')],
+        action: Action::PreviousMatch,
+        description: "previous search match",
+    },
+    Binding {
+        keys: &[key(KeyCode::Up)],
+        action: Action::Up,
+        description: "move up",
+    },
+    Binding {
+        keys: &[key(KeyCode::Down)],
+        action: Action::Down,
+        description: "move down",
+    },
+    Binding {
+        keys: &[key(KeyCode::PageUp)],
+        action: Action::PageUp,
+        description: "move up a page",
+    },
+    Binding {
+        keys: &[key(KeyCode::PageDown)],
+        action: Action::PageDown,
+        description: "move down a page",
+    },
+    Binding {
+        keys: &[key(KeyCode::Home)],
+        action: Action::Top,
+        description: "first row",
+    },
+    Binding {
+        keys: &[key(KeyCode::End)],
+        action: Action::Bottom,
+        description: "last row",
+    },
+    Binding {
+        keys: &[key(KeyCode::Enter), key(KeyCode::Right)],
+        action: Action::Open,
+        description: "open the selected row",
+    },
+    Binding {
+        keys: &[
+            key(KeyCode::Esc),
+            key(KeyCode::Backspace),
+            key(KeyCode::Left),
+        ],
+        action: Action::Back,
+        description: "go back, or clear the search",
+    },
+];
+
+// the Action for a key press, if it's bound
+pub fn action(event: KeyEvent) -> Option<Action> {
+    let pressed = Key {
+        code: event.code,
+        ctrl: event.modifiers.contains(KeyModifiers::CONTROL),
+    };
+
+    BINDINGS
+        .iter()
+        .find(|binding| binding.keys.contains(&pressed))
+        .map(|binding| binding.action)
+}
+
+impl Binding {
+    // eg "q, ctrl-c"
+    pub fn key_names(&self) -> String {
+        let names: Vec<String> = self.keys.iter().map(|key| key.to_string()).collect();
+        names.join(", ")
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.ctrl {
+            write!(f, "ctrl-")?;
+        }
+
+        match self.code {
+            KeyCode::Char(c) => write!(f, "{}", c),
+            KeyCode::PageUp => write!(f, "PgUp"),
+            KeyCode::PageDown => write!(f, "PgDn"),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent { code, modifiers }
+    }
+
+    #[test]
+    fn looks_up_actions() {
+        let none = KeyModifiers::empty();
+
+        assert_eq!(action(event(KeyCode::Char('q'), none)), Some(Action::Quit));
+        assert_eq!(
+            action(event(KeyCode::Char('c'), KeyModifiers::CONTROL)),
+            Some(Action::Quit)
+        );
+        assert_eq!(
+            action(event(KeyCode::Char('c'), none)),
+            Some(Action::Columns)
+        );
+        assert_eq!(action(event(KeyCode::Char('x'), none)), None);
+
+        // shift comes with the capital
+        assert_eq!(
+            action(event(KeyCode::Char('N'), KeyModifiers::SHIFT)),
+            Some(Action::PreviousMatch)
+        );
+    }
+
+    #[test]
+    fn keys_are_bound_once() {
+        let keys: Vec<&Key> = BINDINGS.iter().flat_map(|b| b.keys.iter()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            assert!(!keys[i + 1..].contains(key), "{} is bound twice", key);
+        }
+
+        assert_eq!(BINDINGS[0].key_names(), "q, ctrl-c");
+    }
+}
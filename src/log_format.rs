@@ -0,0 +1,566 @@
+use crate::prelude::*;
+
+use crate::json_format::JsonFormat;
+
+use chrono::TimeZone;
+use regex::Regex;
+
+const MAX_RESPONSE_TIME: std::time::Duration = std::time::Duration::from_secs(86400);
+
+// How input loglines are turned into Requests.
+//
+// Built once at startup from Options and shared between every Logfile reader.
+#[derive(Debug)]
+pub enum LogFormat {
+    // the built-in CLF/combined regex, see Request::parse
+    Clf,
+
+    // compiled from an Apache LogFormat string
+    Apache(CompiledFormat),
+
+    // compiled from an nginx log_format definition
+    Nginx(CompiledFormat),
+
+    // JSON objects, one per line
+    Json(JsonFormat),
+}
+
+impl LogFormat {
+    pub fn from_options(options: &Options) -> Result<Self, Error> {
+        if let Some(ref format) = options.log_format {
+            return Ok(Self::Apache(CompiledFormat::apache(format)?));
+        }
+
+        if let Some(ref format) = options.nginx_format {
+            return Ok(Self::Nginx(CompiledFormat::nginx(format)?));
+        }
+
+        if options.json {
+            return Ok(Self::Json(JsonFormat::new(&options.json_field)?));
+        }
+
+        Ok(Self::Clf)
+    }
+
+    pub fn parse(&self, input: &str) -> Result<Request, Error> {
+        match self {
+            Self::Clf => Request::new(input),
+            Self::Apache(format) | Self::Nginx(format) => format.parse(input),
+            Self::Json(format) => format.parse(input),
+        }
+    }
+}
+
+// The Request field that a single format directive fills in.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Field {
+    IpAddress,
+    Identd,
+    Username,
+    Time,
+    TimeIso8601,
+    TimeMsec,
+    RequestLine,
+    Method,
+    Uri,
+    QueryString,
+    HttpVersion,
+    StatusCode,
+    Size,
+    Referer,
+    UserAgent,
+    ResponseTime,
+    ResponseTimeMillis,
+    ResponseTimeMicros,
+    UpstreamResponseTime,
+
+    // not a Request field; stored in Request::extra under this name
+    Extra(String),
+}
+
+impl Field {
+    pub fn apply(&self, request: &mut Request, value: &str) -> Result<(), Error> {
+        match self {
+            Self::IpAddress => request.ip_address = value.parse()?,
+            Self::Identd => request.identd = Self::optional(value),
+            Self::Username => request.username = Self::optional(value),
+            Self::Time => {
+                request.time = chrono::DateTime::parse_from_str(value, "%d/%b/%Y:%T %z")?;
+            }
+            Self::TimeIso8601 => request.time = chrono::DateTime::parse_from_rfc3339(value)?,
+            Self::TimeMsec => {
+                // seconds since the epoch, eg "1588327200.123"; anything
+                // chrono can't represent is an error rather than a panic
+                let msec = value.parse::<f64>()? * 1000.0;
+                request.time = Some(msec)
+                    .filter(|msec| msec.is_finite() && msec.abs() < i64::MAX as f64)
+                    .and_then(|msec| chrono::Utc.timestamp_millis_opt(msec as i64).single())
+                    .ok_or_else(|| anyhow!("invalid time {}", value))?
+                    .into();
+            }
+            Self::RequestLine => request.set_request_line(value)?,
+            Self::Method => request.method = value.parse()?,
+            Self::Uri => request.uri = String::from(value),
+            Self::QueryString => request.uri.push_str(value),
+            Self::HttpVersion => request.http_version = value.parse()?,
+            Self::StatusCode => request.status_code = value.parse::<i64>()?,
+            Self::Size => request.size = value.parse::<i64>().unwrap_or_default(),
+            Self::Referer => request.referer = String::from(value),
+            Self::UserAgent => request.user_agent = String::from(value),
+            Self::ResponseTime => request.response_time = Self::seconds(value)?,
+            Self::ResponseTimeMillis => {
+                let t = std::time::Duration::from_millis(value.parse()?);
+                request.response_time = Some(Self::response_time(t, value)?);
+            }
+            Self::ResponseTimeMicros => {
+                let t = std::time::Duration::from_micros(value.parse()?);
+                request.response_time = Some(Self::response_time(t, value)?);
+            }
+            Self::UpstreamResponseTime => {
+                // one time per upstream tried, eg "0.010, 0.250 : 0.003"
+                let mut total = None;
+                for t in value.split(&[',', ':'][..]) {
+                    if let Some(t) = Self::seconds(t.trim())? {
+                        total = Some(total.unwrap_or_default() + t);
+                    }
+                }
+                request.upstream_response_time = total;
+            }
+            Self::Extra(name) => {
+                request.extra.insert(name.clone(), String::from(value));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn optional(value: &str) -> Option<String> {
+        match value {
+            "-" => None,
+            x => Some(String::from(x)),
+        }
+    }
+
+    // parse fractional seconds, eg "0.123"; negative, NaN and out of range
+    // values are errors rather than panics, as they come from the log
+    fn seconds(value: &str) -> Result<Option<std::time::Duration>, Error> {
+        match value {
+            "-" | "" => Ok(None),
+            x => {
+                let seconds = x.parse::<f64>()?;
+                let duration = std::time::Duration::try_from_secs_f64(seconds)
+                    .with_context(|| format!("invalid response time {}", x))?;
+                Ok(Some(Self::response_time(duration, x)?))
+            }
+        }
+    }
+
+    // no real response takes a day; capping times keeps the microsecond
+    // sums in Counters well clear of overflowing
+    fn response_time(
+        duration: std::time::Duration,
+        value: &str,
+    ) -> Result<std::time::Duration, Error> {
+        if duration > MAX_RESPONSE_TIME {
+            return Err(anyhow!("response time {} is over a day", value));
+        }
+        Ok(duration)
+    }
+}
+
+// A log format compiled into a single anchored regex, with one capture
+// group per directive. fields[n] describes capture group n + 1.
+#[derive(Debug)]
+pub struct CompiledFormat {
+    regex: Regex,
+    fields: Vec<Field>,
+}
+
+impl CompiledFormat {
+    // Compile an Apache LogFormat string, as it would appear in httpd.conf, eg
+    //   %h %l %u %t \"%r\" %>s %b \"%{Referer}i\" \"%{User-agent}i\"
+    //
+    // A handful of the usual nicknames (common, combined etc) are accepted too.
+    pub fn apache(format: &str) -> Result<Self, Error> {
+        let format = match format {
+            "common" => r#"%h %l %u %t "%r" %>s %b"#,
+            "combined" => r#"%h %l %u %t "%r" %>s %b "%{Referer}i" "%{User-agent}i""#,
+            "vhost_combined" => r#"%v:%p %h %l %u %t "%r" %>s %O "%{Referer}i" "%{User-Agent}i""#,
+            x => x,
+        };
+
+        let mut builder = PatternBuilder::new();
+
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                // backslash escapes, as written inside a quoted httpd.conf string
+                '\\' => match chars.next() {
+                    Some('n') => builder.literal('\n'),
+                    Some('t') => builder.literal('\t'),
+                    Some(x) => builder.literal(x),
+                    None => builder.literal('\\'),
+                },
+
+                '%' if chars.peek() == Some(&'%') => {
+                    chars.next();
+                    builder.literal('%');
+                }
+
+                '%' => {
+                    // skip modifiers: %>s, %<s, %!200,304{Referer}i etc
+                    while let Some('<') | Some('>') | Some('!') | Some(',') | Some('0'..='9') =
+                        chars.peek()
+                    {
+                        chars.next();
+                    }
+
+                    let mut param = None;
+                    if chars.peek() == Some(&'{') {
+                        chars.next();
+                        let p: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                        param = Some(p);
+                    }
+
+                    let directive = chars
+                        .next()
+                        .ok_or_else(|| anyhow!("log format ends with incomplete directive"))?;
+
+                    let (field, capture) = Self::apache_directive(directive, param.as_deref());
+                    builder.field(field, capture);
+                }
+
+                x => builder.literal(x),
+            }
+        }
+
+        builder.finish(format)
+    }
+
+    // Compile an nginx log_format definition (just the format string(s), without
+    // the log_format keyword and name), eg
+    //   $remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent
+    //
+    // The nicknames combined and main (the default in nginx.conf) are accepted too.
+    pub fn nginx(format: &str) -> Result<Self, Error> {
+        let format = match format {
+            "combined" => {
+                r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#
+            }
+            "main" => {
+                r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent" "$http_x_forwarded_for""#
+            }
+            x => x,
+        };
+
+        let mut builder = PatternBuilder::new();
+
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '$' => {
+                    // $name or ${name}
+                    let braced = chars.peek() == Some(&'{');
+                    if braced {
+                        chars.next();
+                    }
+
+                    let mut name = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if !(c.is_ascii_alphanumeric() || c == '_') {
+                            break;
+                        }
+                        name.push(c);
+                        chars.next();
+                    }
+
+                    if braced && chars.next() != Some('}') {
+                        return Err(anyhow!("unterminated ${{ in log format"));
+                    }
+
+                    if name.is_empty() {
+                        builder.literal('$');
+                        continue;
+                    }
+
+                    let (field, capture) = Self::nginx_variable(&name);
+                    builder.field(field, capture);
+                }
+
+                x => builder.literal(x),
+            }
+        }
+
+        builder.finish(format)
+    }
+
+    // Map one Apache directive (and its optional {param}) onto a Request field,
+    // and the capture group used to match it.
+    fn apache_directive(directive: char, param: Option<&str>) -> (Field, &'static str) {
+        const TOKEN: &str = r"(\S*)";
+        const TEXT: &str = r"(.*?)";
+
+        let header = param.map(|p| p.to_ascii_lowercase());
+
+        match (directive, header.as_deref()) {
+            ('a', _) | ('h', _) => (Field::IpAddress, TOKEN),
+            ('l', None) => (Field::Identd, TOKEN),
+            ('u', None) => (Field::Username, TOKEN),
+            ('t', None) => (Field::Time, r"\[([^\]]*)\]"),
+            ('r', None) => (Field::RequestLine, TEXT),
+            ('m', None) => (Field::Method, TOKEN),
+            ('U', None) => (Field::Uri, TOKEN),
+            ('q', None) => (Field::QueryString, TOKEN),
+            ('H', None) => (Field::HttpVersion, TOKEN),
+            ('s', None) => (Field::StatusCode, TOKEN),
+            ('b', None) | ('B', None) | ('O', None) => (Field::Size, TOKEN),
+            ('D', None) | ('T', Some("us")) => (Field::ResponseTimeMicros, TOKEN),
+            ('T', Some("ms")) => (Field::ResponseTimeMillis, TOKEN),
+            ('T', None) | ('T', Some("s")) => (Field::ResponseTime, TOKEN),
+            ('i', Some("referer")) => (Field::Referer, TEXT),
+            ('i', Some("user-agent")) => (Field::UserAgent, TEXT),
+
+            // anything else is kept as a named extra field; free-text
+            // directives get a lazy match, the rest are single tokens
+            (d, _) => {
+                let name = match param {
+                    Some(p) => format!("{{{}}}{}", p, d),
+                    None => d.to_string(),
+                };
+
+                let capture = match d {
+                    'i' | 'o' | 'e' | 'n' | 'C' | 't' | 'r' => TEXT,
+                    _ => TOKEN,
+                };
+
+                (Field::Extra(name), capture)
+            }
+        }
+    }
+
+    // Map one nginx variable onto a Request field, and the capture group used
+    // to match it.
+    fn nginx_variable(name: &str) -> (Field, &'static str) {
+        const TOKEN: &str = r"(\S*)";
+        const TEXT: &str = r"(.*?)";
+
+        match name {
+            "remote_addr" | "realip_remote_addr" => (Field::IpAddress, TOKEN),
+            "remote_user" => (Field::Username, TOKEN),
+            "time_local" => (Field::Time, r"(\S+ [+\-]\d{4})"),
+            "time_iso8601" => (Field::TimeIso8601, TOKEN),
+            "msec" => (Field::TimeMsec, TOKEN),
+            "request" => (Field::RequestLine, TEXT),
+            "request_method" => (Field::Method, TOKEN),
+            "request_uri" | "uri" | "document_uri" => (Field::Uri, TOKEN),
+            "is_args" | "args" | "query_string" => (Field::QueryString, TOKEN),
+            "server_protocol" => (Field::HttpVersion, TOKEN),
+            "status" => (Field::StatusCode, TOKEN),
+            "body_bytes_sent" | "bytes_sent" => (Field::Size, TOKEN),
+            "http_referer" => (Field::Referer, TEXT),
+            "http_user_agent" => (Field::UserAgent, TEXT),
+            "request_time" => (Field::ResponseTime, TOKEN),
+
+            // one entry per upstream tried, separated by ", " or " : "
+            "upstream_response_time" => (
+                Field::UpstreamResponseTime,
+                r"(-|[\d.]+(?:(?:, | : )(?:-|[\d.]+))*)",
+            ),
+
+            // headers and cookies can contain anything; other variables are tokens
+            x if x.starts_with("http_")
+                || x.starts_with("sent_http_")
+                || x.starts_with("cookie_") =>
+            {
+                (Field::Extra(String::from(x)), TEXT)
+            }
+            x => (Field::Extra(String::from(x)), TOKEN),
+        }
+    }
+
+    fn parse(&self, input: &str) -> Result<Request, Error> {
+        let captures = self
+            .regex
+            .captures(input)
+            .ok_or_else(|| anyhow!("log format did not match input"))?;
+
+        let mut request = Request::blank();
+
+        for (i, field) in self.fields.iter().enumerate() {
+            // every directive is a mandatory capture group, so this can't fail
+            let value = captures.get(i + 1).unwrap().as_str();
+            field.apply(&mut request, value)?;
+        }
+
+        Ok(request)
+    }
+}
+
+// Accumulates the regex for a CompiledFormat while a format string is
+// tokenised, escaping literal text between fields.
+struct PatternBuilder {
+    pattern: String,
+    literal: String,
+    fields: Vec<Field>,
+}
+
+impl PatternBuilder {
+    fn new() -> Self {
+        Self {
+            pattern: String::from("^"),
+            literal: String::new(),
+            fields: vec![],
+        }
+    }
+
+    fn literal(&mut self, c: char) {
+        self.literal.push(c);
+    }
+
+    fn field(&mut self, field: Field, capture: &str) {
+        self.pattern.push_str(&regex::escape(&self.literal));
+        self.literal.clear();
+
+        self.pattern.push_str(capture);
+        self.fields.push(field);
+    }
+
+    fn finish(mut self, format: &str) -> Result<CompiledFormat, Error> {
+        self.pattern.push_str(&regex::escape(&self.literal));
+        self.pattern.push('$');
+
+        if self.fields.is_empty() {
+            return Err(anyhow!("log format contains no directives: {}", format));
+        }
+
+        debug!("compiled log format {} to {}", format, self.pattern);
+
+        Ok(CompiledFormat {
+            regex: Regex::new(&self.pattern).context("failed to compile log format")?,
+            fields: self.fields,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::request::HttpMethod;
+
+    use std::time::Duration;
+
+    #[test]
+    fn compiles_common_format() {
+        let format = CompiledFormat::apache(r#"%h %l %u %t "%r" %>s %b"#).unwrap();
+        let r = format
+            .parse(r#"10.0.0.1 - frank [01/May/2020:10:00:00 +0100] "POST /login HTTP/1.0" 302 -"#)
+            .unwrap();
+
+        assert_eq!(r.ip_address, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(r.identd, None);
+        assert_eq!(r.username.as_deref(), Some("frank"));
+        assert_eq!(r.time.to_rfc3339(), "2020-05-01T10:00:00+01:00");
+        assert_eq!(r.method, HttpMethod::POST);
+        assert_eq!(r.uri, "/login");
+        assert_eq!(r.status_code, 302);
+        assert_eq!(r.size, 0);
+
+        assert!(format.parse("10.0.0.1 - - garbage").is_err());
+    }
+
+    #[test]
+    fn headers_go_to_fields_or_extra() {
+        let format =
+            CompiledFormat::apache(r#"%h "%{Referer}i" "%{User-agent}i" "%{X-Forwarded-For}i""#)
+                .unwrap();
+        let r = format
+            .parse(r#"10.0.0.1 "http://example.com/" "curl/7.68.0" "192.168.1.1, 10.0.0.2""#)
+            .unwrap();
+
+        assert_eq!(r.referer, "http://example.com/");
+        assert_eq!(r.user_agent, "curl/7.68.0");
+        assert_eq!(
+            r.extra.get("{X-Forwarded-For}i").map(String::as_str),
+            Some("192.168.1.1, 10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn response_time_units() {
+        let time = |format: &str, value: &str| {
+            let format = CompiledFormat::apache(&format!("%h {}", format)).unwrap();
+            format
+                .parse(&format!("10.0.0.1 {}", value))
+                .unwrap()
+                .response_time
+        };
+
+        // %D is microseconds, %T whole (or fractional) seconds
+        assert_eq!(time("%D", "1500"), Some(Duration::from_micros(1500)));
+        assert_eq!(time("%T", "2"), Some(Duration::from_secs(2)));
+        assert_eq!(time("%{ms}T", "250"), Some(Duration::from_millis(250)));
+        assert_eq!(time("%{us}T", "250"), Some(Duration::from_micros(250)));
+        assert_eq!(time("%T", "-"), None);
+    }
+
+    #[test]
+    fn bad_response_times_are_errors() {
+        let format = CompiledFormat::apache("%h %T").unwrap();
+        for value in ["-1", "NaN", "inf", "1e300", "-0.5", "1e15", "86401"].iter() {
+            assert!(format.parse(&format!("10.0.0.1 {}", value)).is_err());
+        }
+
+        let format = CompiledFormat::apache("%h %D").unwrap();
+        assert!(format.parse("10.0.0.1 18446744073709551615").is_err());
+        assert!(format.parse("10.0.0.1 86400000000").is_ok());
+    }
+
+    #[test]
+    fn nginx_main_and_upstream_times() {
+        let format = CompiledFormat::nginx("main").unwrap();
+        let r = format
+            .parse(r#"10.0.0.1 - bob [01/May/2020:10:00:00 +0000] "GET /a?b=1 HTTP/1.1" 200 512 "-" "curl/7.68.0" "192.168.1.1""#)
+            .unwrap();
+
+        assert_eq!(r.username.as_deref(), Some("bob"));
+        assert_eq!(r.uri, "/a?b=1");
+        assert_eq!(r.size, 512);
+        assert_eq!(r.user_agent, "curl/7.68.0");
+        assert_eq!(
+            r.extra.get("http_x_forwarded_for").map(String::as_str),
+            Some("192.168.1.1")
+        );
+
+        // one time per upstream tried, summed
+        let format =
+            CompiledFormat::nginx("$remote_addr $request_time $upstream_response_time").unwrap();
+        let r = format.parse("10.0.0.1 0.300 0.010, 0.250 : -").unwrap();
+        assert_eq!(r.response_time, Some(Duration::from_millis(300)));
+        assert_eq!(r.upstream_response_time, Some(Duration::from_millis(260)));
+    }
+
+    #[test]
+    fn msec_times() {
+        let format = CompiledFormat::nginx("$remote_addr $msec").unwrap();
+        let r = format.parse("10.0.0.1 1588327200.123").unwrap();
+        assert_eq!(r.time.to_rfc3339(), "2020-05-01T10:00:00.123+00:00");
+
+        for value in ["1e300", "-1e300", "inf", "NaN", "1e15"].iter() {
+            assert!(format.parse(&format!("10.0.0.1 {}", value)).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_bad_formats() {
+        assert!(CompiledFormat::apache("%h %").is_err());
+        assert!(CompiledFormat::apache("%h %{Referer").is_err());
+        assert!(CompiledFormat::apache("no directives here").is_err());
+        assert!(CompiledFormat::nginx("$remote_addr ${status").is_err());
+
+        // unknown directives are kept, not rejected
+        let format = CompiledFormat::apache("%h %v").unwrap();
+        let r = format.parse("10.0.0.1 example.com").unwrap();
+        assert_eq!(r.extra.get("v").map(String::as_str), Some("example.com"));
+    }
+}
@@ -8,58 +8,709 @@ use bstr::io::BufReadExt;
 
 pub struct Logfile {
     pub path: std::path::PathBuf,
+
+    // compressed, so read once rather than followed
+    pub archive: bool,
+
+    reader: thread::JoinHandle<Result<(), Error>>,
+}
+
+// Where a new Logfile reader starts reading, before following the file.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum StartFrom {
+    Beginning,
+    End,
+
+    // backfill the last n lines
+    Lines(u64),
+
+    // backfill lines logged in the last n seconds
+    Seconds(u64),
 }
 
 impl Logfile {
-    pub fn new(path: std::path::PathBuf, request_tx: Sender<Request>) -> Result<Self, Error> {
-        Self::start_reader(request_tx, &path)?;
+    pub fn new(
+        path: std::path::PathBuf,
+        log_format: Arc<LogFormat>,
+        request_tx: Sender<Request>,
+        start_from: StartFrom,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Self, Error> {
+        let reader = Self::start_reader(log_format, request_tx, &path, start_from, now)?;
+        let archive = Self::is_archive(&path);
+
+        Ok(Logfile {
+            path,
+            archive,
+            reader,
+        })
+    }
 
-        Ok(Logfile { path })
+    // The reader thread stops by itself when its file is deleted, or when
+    // it reaches the end of a compressed file.
+    pub fn is_finished(&self) -> bool {
+        self.reader.is_finished()
     }
 
     fn start_reader(
+        log_format: Arc<LogFormat>,
         request_tx: Sender<Request>,
         path: &std::path::PathBuf,
+        start_from: StartFrom,
+        now: chrono::DateTime<chrono::Utc>,
     ) -> Result<thread::JoinHandle<Result<(), Error>>, Error> {
-        let mut fh = std::fs::File::open(&path)
+        let mut fh = std::fs::File::open(path)
             .with_context(|| format!("failed to open input logfile {}", &path.display()))?;
 
-        // ignore failing seek result - this lets us operate on stdin/pipes
-        let _ = fh.seek(std::io::SeekFrom::End(0));
+        if Self::is_archive(path) {
+            let decoder = Self::decoder(path, fh)?;
+            return Self::start_archive_reader(log_format, request_tx, path, decoder);
+        }
+
+        // backfilling only makes sense for regular files; pipes just carry on
+        let start_from = match fh.metadata() {
+            Ok(m) if m.is_file() => start_from,
+            _ => StartFrom::End,
+        };
 
+        let offset = match start_from {
+            StartFrom::Beginning => 0,
+            StartFrom::Lines(n) => Self::find_last_lines(&mut fh, n)?,
+            StartFrom::Seconds(n) => Self::find_time(&mut fh, &log_format, n, now)?,
+            StartFrom::End => {
+                // ignore failing seek result - this lets us operate on stdin/pipes
+                let _ = fh.seek(std::io::SeekFrom::End(0));
+                0
+            }
+        };
+
+        if start_from != StartFrom::End {
+            debug!(
+                "{} starting from offset {} ({:?})",
+                path.display(),
+                offset,
+                start_from
+            );
+            fh.seek(std::io::SeekFrom::Start(offset))?;
+        }
+
+        let mut waiter = Waiter::new(path, &fh);
         let mut br = std::io::BufReader::new(fh);
 
-        let c = move || -> Result<_, _> {
-            let sleep = std::time::Duration::from_millis(100);
+        let thread_name = format!("log_reader ({})", &path.display());
 
+        let path = path.clone();
+        let c = move || -> Result<_, _> {
             loop {
-                br.by_ref().for_byte_line(|input| {
-                    let line = String::from_utf8(input.to_vec());
-                    if line.is_err() {
-                        error!("logline not UTF-8: {:?}", line);
-                        return Ok(true);
+                Self::read_lines(&mut br, &log_format, &request_tx)?;
+
+                // at EOF; if the file was rotated, carry straight on with the new one
+                match Self::check_rotation(&path, &mut br) {
+                    Rotation::Unchanged => {}
+                    Rotation::Reopened(fh) => {
+                        waiter = Waiter::new(&path, &fh);
+                        br = std::io::BufReader::new(fh);
+                        continue;
+                    }
+                    Rotation::Deleted => {
+                        info!("{} was deleted, no longer reading it", path.display());
+                        return Ok(());
+                    }
+                }
+
+                waiter.wait()?;
+            }
+        };
+
+        Ok(thread::Builder::new().name(thread_name).spawn(c)?)
+    }
+
+    // Compressed (rotated) logfiles are read once, start to finish, and not
+    // followed afterwards.
+    fn start_archive_reader(
+        log_format: Arc<LogFormat>,
+        request_tx: Sender<Request>,
+        path: &std::path::Path,
+        decoder: Box<dyn Read + Send>,
+    ) -> Result<thread::JoinHandle<Result<(), Error>>, Error> {
+        let mut br = std::io::BufReader::new(decoder);
+
+        let thread_name = format!("archive_reader ({})", &path.display());
+
+        let path = path.to_path_buf();
+        let c = move || -> Result<_, _> {
+            Self::read_lines(&mut br, &log_format, &request_tx)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+
+            info!("finished reading {}", path.display());
+            Ok(())
+        };
+
+        Ok(thread::Builder::new().name(thread_name).spawn(c)?)
+    }
+
+    // Open path to be read once from start to finish, decompressing it if
+    // needed. Used by report mode, which doesn't follow files.
+    pub fn open_to_read(path: &std::path::Path) -> Result<Box<dyn BufRead>, Error> {
+        let fh = std::fs::File::open(path)
+            .with_context(|| format!("failed to open input logfile {}", path.display()))?;
+
+        if Self::is_archive(path) {
+            Ok(Box::new(std::io::BufReader::new(Self::decoder(path, fh)?)))
+        } else {
+            Ok(Box::new(std::io::BufReader::new(fh)))
+        }
+    }
+
+    pub fn is_archive(path: &std::path::Path) -> bool {
+        matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("gz") | Some("zst") | Some("bz2")
+        )
+    }
+
+    // Pick a decompressor for fh based on the extension of path.
+    fn decoder(path: &std::path::Path, fh: std::fs::File) -> Result<Box<dyn Read + Send>, Error> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Ok(Box::new(flate2::read::MultiGzDecoder::new(fh))),
+            Some("zst") => Ok(Box::new(zstd::stream::read::Decoder::new(fh)?)),
+            Some("bz2") => Ok(Box::new(bzip2::read::MultiBzDecoder::new(fh))),
+            _ => Err(anyhow!("unknown compression for {}", path.display())),
+        }
+    }
+
+    // Parse every complete line available from br, and pass the resulting
+    // Requests on, stopping early if nothing is receiving them any more.
+    pub fn read_lines<R: BufRead>(
+        br: &mut R,
+        log_format: &LogFormat,
+        request_tx: &Sender<Request>,
+    ) -> Result<(), Error> {
+        br.for_byte_line(|input| {
+            let line = String::from_utf8(input.to_vec());
+            if line.is_err() {
+                error!("logline not UTF-8: {:?}", line);
+                return Ok(true);
+            }
+
+            let line = line.unwrap();
+            //debug!("logline: {}", line);
+
+            match log_format.parse(&line) {
+                Ok(request) => {
+                    if request_tx.send(request).is_err() {
+                        return Ok(false);
                     }
+                }
+                Err(e) => {
+                    error!("unparseable logline ({}) :: {}", e, line);
+                }
+            }
+
+            Ok(true)
+        })?;
+
+        Ok(())
+    }
+
+    // Offset of the start of the nth line from the end, found by scanning
+    // backwards through the file a block at a time.
+    fn find_last_lines(fh: &mut std::fs::File, lines: u64) -> Result<u64, Error> {
+        const BLOCK: u64 = 65536;
+
+        let len = fh.metadata()?.len();
+
+        // no lines at all, so start at the end
+        if lines == 0 {
+            return Ok(len);
+        }
+
+        let mut buffer = vec![0; BLOCK as usize];
+
+        // newlines seen so far; a trailing newline doesn't start another line
+        let mut newlines = 0;
+        let mut end = len;
+        let mut skip_trailing = true;
+
+        while end > 0 {
+            let start = end.saturating_sub(BLOCK);
+            let block = &mut buffer[..(end - start) as usize];
+
+            fh.seek(std::io::SeekFrom::Start(start))?;
+            fh.read_exact(block)?;
+
+            for (i, byte) in block.iter().enumerate().rev() {
+                if *byte != b'\n' {
+                    skip_trailing = false;
+                    continue;
+                }
+
+                if skip_trailing {
+                    skip_trailing = false;
+                    continue;
+                }
+
+                newlines += 1;
+                if newlines == lines {
+                    return Ok(start + i as u64 + 1);
+                }
+            }
+
+            end = start;
+        }
+
+        Ok(0)
+    }
+
+    // Offset of the first line logged less than seconds before now, found by
+    // binary searching the file on each line's timestamp. Assumes lines are
+    // (roughly) in time order; lines which fail to parse are treated as too old.
+    fn find_time(
+        fh: &mut std::fs::File,
+        log_format: &LogFormat,
+        seconds: u64,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<u64, Error> {
+        let cutoff = now - chrono::Duration::seconds(seconds as i64);
+
+        // the answer is always the start of the first line at or after lo
+        let mut lo = 0;
+        let mut hi = fh.metadata()?.len();
 
-                    let line = line.unwrap();
-                    //debug!("logline: {}", line);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
 
-                    match Request::new(&line) {
+            match Self::line_at(fh, mid)? {
+                None => hi = mid,
+                Some((_, end, line)) => {
+                    let recent = match log_format.parse(&line) {
                         Ok(request) => {
-                            request_tx.send(request).unwrap();
-                        }
-                        Err(e) => {
-                            error!("unparseable logline ({}) :: {}", e, line);
+                            chrono::DateTime::<chrono::Utc>::from(request.time) >= cutoff
                         }
+                        Err(_) => false,
+                    };
+
+                    if recent {
+                        hi = mid;
+                    } else {
+                        lo = end;
                     }
+                }
+            }
+        }
+
+        match Self::line_at(fh, lo)? {
+            Some((start, _, _)) => Ok(start),
+            None => Ok(lo),
+        }
+    }
+
+    // The first complete line starting at or after offset, as (start, end, line),
+    // where end is the offset of the following line.
+    fn line_at(fh: &mut std::fs::File, offset: u64) -> Result<Option<(u64, u64, String)>, Error> {
+        // start one byte early, so if offset is already the start of a line
+        // we find the newline before it
+        let mut start = offset.saturating_sub(1);
+        fh.seek(std::io::SeekFrom::Start(start))?;
+
+        let mut br = std::io::BufReader::new(fh);
+        let mut buffer = vec![];
+
+        if offset > 0 {
+            start += br.read_until(b'\n', &mut buffer)? as u64;
+            buffer.clear();
+        }
+
+        let len = br.read_until(b'\n', &mut buffer)? as u64;
+
+        // EOF, or a partial line still being written
+        if len == 0 || buffer.last() != Some(&b'\n') {
+            return Ok(None);
+        }
+
+        let line = String::from_utf8_lossy(&buffer).trim_end().to_string();
+        Ok(Some((start, start + len, line)))
+    }
+
+    // Called at EOF. If path now refers to a different file than the one we're
+    // reading (logrotate's default create mode), return the new one, opened
+    // at the start. If our file has shrunk (copytruncate), rewind it.
+    //
+    // Pipes and other non-regular files are left alone.
+    fn check_rotation(
+        path: &std::path::Path,
+        br: &mut std::io::BufReader<std::fs::File>,
+    ) -> Rotation {
+        let current = match br.get_ref().metadata() {
+            Ok(m) if m.is_file() => m,
+            _ => return Rotation::Unchanged,
+        };
 
-                    Ok(true)
-                })?;
+        let on_disk = match std::fs::metadata(path) {
+            Ok(m) => m,
 
-                thread::sleep(sleep);
+            // gone, and not renamed elsewhere either
+            Err(_) if Self::is_unlinked(&current) => return Rotation::Deleted,
+
+            // otherwise we're probably mid-rotation; keep reading the old
+            // file until a new one appears
+            Err(_) => return Rotation::Unchanged,
+        };
+
+        if Self::file_id(&current) != Self::file_id(&on_disk) {
+            info!("{} was rotated, reopening", path.display());
+
+            return match std::fs::File::open(path) {
+                Ok(fh) => Rotation::Reopened(fh),
+                Err(e) => {
+                    error!("failed to reopen {}: {}", path.display(), e);
+                    Rotation::Unchanged
+                }
+            };
+        }
+
+        let position = match br.stream_position() {
+            Ok(p) => p,
+            Err(_) => return Rotation::Unchanged,
+        };
+
+        if on_disk.len() < position {
+            info!(
+                "{} was truncated ({} < {} bytes), reading from start",
+                path.display(),
+                on_disk.len(),
+                position
+            );
+
+            if let Err(e) = br.seek(std::io::SeekFrom::Start(0)) {
+                error!("failed to rewind {}: {}", path.display(), e);
+            }
+        }
+
+        Rotation::Unchanged
+    }
+
+    // something to identify a file by, independently of its path
+    #[cfg(unix)]
+    pub fn file_id(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+        use std::os::unix::fs::MetadataExt;
+        Some((metadata.dev(), metadata.ino()))
+    }
+
+    #[cfg(not(unix))]
+    pub fn file_id(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+        None
+    }
+
+    // true if no path refers to this file any more
+    #[cfg(unix)]
+    fn is_unlinked(metadata: &std::fs::Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        metadata.nlink() == 0
+    }
+
+    #[cfg(not(unix))]
+    fn is_unlinked(_metadata: &std::fs::Metadata) -> bool {
+        false
+    }
+}
+
+// What check_rotation found at EOF.
+enum Rotation {
+    Unchanged,
+    Reopened(std::fs::File),
+    Deleted,
+}
+
+// Blocks a reader thread at EOF until there might be more to read.
+//
+// On Linux, regular files are watched with inotify, so we wake as soon as
+// the file is written, renamed or deleted. Everything else (pipes, other
+// platforms, filesystems inotify won't watch) polls every 100ms.
+enum Waiter {
+    // dir and name pick out the directory events about our own path, so
+    // other files coming and going next to it don't wake us
+    #[cfg(target_os = "linux")]
+    Inotify {
+        inotify: inotify::Inotify,
+        buffer: Vec<u8>,
+        dir: inotify::WatchDescriptor,
+        name: Option<std::ffi::OsString>,
+    },
+
+    Sleep(std::time::Duration),
+}
+
+impl Waiter {
+    fn new(path: &std::path::Path, fh: &std::fs::File) -> Self {
+        #[cfg(target_os = "linux")]
+        {
+            if fh.metadata().map(|m| m.is_file()).unwrap_or(false) {
+                match Self::inotify(path) {
+                    Ok(waiter) => return waiter,
+                    Err(e) => info!(
+                        "not watching {} with inotify ({}), polling",
+                        path.display(),
+                        e
+                    ),
+                }
             }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        let _ = (path, fh);
+
+        Self::Sleep(std::time::Duration::from_millis(100))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn inotify(path: &std::path::Path) -> Result<Self, Error> {
+        use inotify::{Inotify, WatchMask};
+
+        let mut inotify = Inotify::init()?;
+
+        inotify.add_watch(
+            path,
+            WatchMask::MODIFY | WatchMask::ATTRIB | WatchMask::MOVE_SELF | WatchMask::DELETE_SELF,
+        )?;
+
+        // watch the directory too, so we notice a new file appearing at path
+        // after the one we're reading has been rotated away
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
         };
+        let dir = inotify.add_watch(dir, WatchMask::CREATE | WatchMask::MOVED_TO)?;
 
-        let thread_name = format!("log_reader ({})", &path.display());
-        Ok(thread::Builder::new().name(thread_name).spawn(c)?)
+        Ok(Self::Inotify {
+            inotify,
+            buffer: vec![0; 4096],
+            dir,
+            name: path.file_name().map(|name| name.to_os_string()),
+        })
+    }
+
+    fn wait(&mut self) -> Result<(), Error> {
+        match self {
+            // we don't care which event it was, so long as it was about our
+            // file; the caller rechecks everything
+            #[cfg(target_os = "linux")]
+            Self::Inotify {
+                inotify,
+                buffer,
+                dir,
+                name,
+            } => loop {
+                let mut events = inotify.read_events_blocking(buffer)?;
+                if events.any(|e| e.wd != *dir || name.is_none() || e.name == name.as_deref()) {
+                    break;
+                }
+            },
+
+            Self::Sleep(duration) => thread::sleep(*duration),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, contents: &str) -> std::fs::File {
+        let path = std::env::temp_dir().join(format!("apachetop-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+
+        let fh = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        fh
+    }
+
+    // an empty directory of its own for each test, as rotation renames and
+    // recreates files by name
+    fn directory(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("apachetop-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    // a reader of path, at its end as the reader thread would be
+    fn read_to_end(path: &std::path::Path) -> std::io::BufReader<std::fs::File> {
+        let mut br = std::io::BufReader::new(std::fs::File::open(path).unwrap());
+        br.read_to_end(&mut vec![]).unwrap();
+        br
+    }
+
+    #[test]
+    fn rewinds_truncated_files() {
+        let dir = directory("truncated");
+        let path = dir.join("access.log");
+        std::fs::write(&path, "one\ntwo\n").unwrap();
+
+        let mut br = read_to_end(&path);
+        assert!(matches!(
+            Logfile::check_rotation(&path, &mut br),
+            Rotation::Unchanged
+        ));
+        assert_eq!(br.stream_position().unwrap(), 8);
+
+        // copytruncate
+        std::fs::write(&path, "").unwrap();
+        assert!(matches!(
+            Logfile::check_rotation(&path, &mut br),
+            Rotation::Unchanged
+        ));
+        assert_eq!(br.stream_position().unwrap(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopens_rotated_files() {
+        let dir = directory("rotated");
+        let path = dir.join("access.log");
+        std::fs::write(&path, "old\n").unwrap();
+        let mut br = read_to_end(&path);
+
+        // renamed away, but no new file yet
+        std::fs::rename(&path, dir.join("access.log.1")).unwrap();
+        assert!(matches!(
+            Logfile::check_rotation(&path, &mut br),
+            Rotation::Unchanged
+        ));
+
+        std::fs::write(&path, "new\n").unwrap();
+        match Logfile::check_rotation(&path, &mut br) {
+            Rotation::Reopened(fh) => {
+                let mut line = String::new();
+                std::io::BufReader::new(fh).read_line(&mut line).unwrap();
+                assert_eq!(line, "new\n");
+            }
+            _ => panic!("rotated file not reopened"),
+        }
+
+        // deleted outright, rather than rotated
+        let mut br = read_to_end(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            Logfile::check_rotation(&path, &mut br),
+            Rotation::Deleted
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn wakes_for_its_own_file_only() {
+        let dir = directory("waiter");
+        let path = dir.join("access.log");
+        std::fs::write(&path, "").unwrap();
+
+        let mut waiter = Waiter::new(&path, &std::fs::File::open(&path).unwrap());
+        assert!(matches!(waiter, Waiter::Inotify { .. }));
+
+        let (woken_tx, woken_rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            waiter.wait().unwrap();
+            woken_tx.send(()).unwrap();
+        });
+
+        // eg another vhost's log appearing
+        std::fs::write(dir.join("other.log"), "").unwrap();
+        let timeout = std::time::Duration::from_millis(200);
+        assert!(woken_rx.recv_timeout(timeout).is_err());
+
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"line\n")
+            .unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        assert!(woken_rx.recv_timeout(timeout).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_compressed_files() {
+        let dir = directory("compressed");
+        let contents = b"one\ntwo\n";
+
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gz.write_all(contents).unwrap();
+        let mut bz2 = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::default());
+        bz2.write_all(contents).unwrap();
+
+        for (name, compressed) in [
+            ("access.log.1.gz", gz.finish().unwrap()),
+            (
+                "access.log.1.zst",
+                zstd::encode_all(&contents[..], 0).unwrap(),
+            ),
+            ("access.log.1.bz2", bz2.finish().unwrap()),
+        ]
+        .iter()
+        {
+            let path = dir.join(name);
+            std::fs::write(&path, compressed).unwrap();
+            assert!(Logfile::is_archive(&path));
+
+            let mut lines = vec![];
+            Logfile::open_to_read(&path)
+                .unwrap()
+                .read_to_end(&mut lines)
+                .unwrap();
+            assert_eq!(lines, contents, "{}", name);
+        }
+
+        assert!(!Logfile::is_archive(&dir.join("access.log.1")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn finds_time_relative_to_now() {
+        let lines: String = (0..10)
+            .map(|n| {
+                format!(
+                    "10.0.0.1 - - [01/May/2020:10:00:0{} +0000] \"GET / HTTP/1.1\" 200 1\n",
+                    n
+                )
+            })
+            .collect();
+        let line_len = lines.len() as u64 / 10;
+        let mut fh = file("find-time", &lines);
+
+        let now = chrono::DateTime::parse_from_rfc3339("2020-05-01T10:00:10Z")
+            .unwrap()
+            .into();
+        let find = |fh: &mut std::fs::File, seconds| {
+            Logfile::find_time(fh, &LogFormat::Clf, seconds, now).unwrap()
+        };
+
+        assert_eq!(find(&mut fh, 5), 5 * line_len);
+        assert_eq!(find(&mut fh, 60), 0);
+        assert_eq!(find(&mut fh, 0), 10 * line_len);
+    }
+
+    #[test]
+    fn finds_last_lines() {
+        let mut fh = file("last-lines", "one\ntwo\nthree\n");
+
+        assert_eq!(Logfile::find_last_lines(&mut fh, 1).unwrap(), 8);
+        assert_eq!(Logfile::find_last_lines(&mut fh, 2).unwrap(), 4);
+        assert_eq!(Logfile::find_last_lines(&mut fh, 3).unwrap(), 0);
+        assert_eq!(Logfile::find_last_lines(&mut fh, 10).unwrap(), 0);
+
+        // --backfill 0 reads nothing
+        assert_eq!(Logfile::find_last_lines(&mut fh, 0).unwrap(), 14);
+        assert_eq!(
+            Logfile::find_last_lines(&mut file("empty", ""), 0).unwrap(),
+            0
+        );
     }
 }
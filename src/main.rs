@@ -1,11 +1,24 @@
 mod app;
+mod batch;
+mod clock;
+mod columns;
+mod filter;
 mod grouped_stats;
+mod histogram;
+mod inputs;
+mod json_format;
+mod keys;
+mod log_format;
 mod logfile;
+mod metrics;
 mod options;
+mod prefilter;
 mod prelude;
+mod report;
 mod request;
 mod ring_buffer;
 mod stats;
+mod statsd;
 mod window;
 
 use crate::prelude::*;
@@ -18,7 +31,19 @@ fn main() {
 }
 
 fn try_main() -> Result<(), Error> {
-    let mut app = App::new()?;
+    let mut options = Options::new()?;
+    App::setup_logger(&options.debug)?;
+
+    debug!("{:?}", options);
+
+    // TODO: should probably abort process when a thread crashes?
+    log_panics::init();
+
+    if let Some(Command::Report { top, file }) = options.command.take() {
+        return Report::new(options, file, top).run();
+    }
+
+    let mut app = App::new(options)?;
 
     app.start()?;
 
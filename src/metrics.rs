@@ -0,0 +1,457 @@
+use crate::prelude::*;
+
+use crate::stats::Counters;
+
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+
+// Serves Prometheus metrics on --metrics, alongside the Window or Batch
+// output: counters from alltime_stats, gauges for the current window, and
+// optionally the top --metrics-top rows of the current grouping.
+pub struct Metrics {
+    options: Arc<Mutex<Options>>,
+    clock: Arc<dyn Clock>,
+
+    alltime_stats: Arc<Mutex<Stats>>,
+    ring_buffer: Arc<Mutex<RingBuffer>>,
+}
+
+// label values for Stats::by_status_code
+const STATUS_CLASSES: [&str; 6] = ["other", "1xx", "2xx", "3xx", "4xx", "5xx"];
+
+impl Metrics {
+    pub fn new(
+        options: Arc<Mutex<Options>>,
+        clock: Arc<dyn Clock>,
+        alltime_stats: Arc<Mutex<Stats>>,
+        ring_buffer: Arc<Mutex<RingBuffer>>,
+    ) -> Self {
+        Self {
+            options,
+            clock,
+            alltime_stats,
+            ring_buffer,
+        }
+    }
+
+    // Bind now, so a bad address is reported at startup, then answer
+    // scrapes one at a time in a new thread.
+    pub fn serve(
+        self,
+        addr: std::net::SocketAddr,
+    ) -> Result<thread::JoinHandle<Result<(), Error>>, Error> {
+        let listener = TcpListener::bind(addr)
+            .with_context(|| format!("failed to listen for metrics on {}", addr))?;
+        info!("serving metrics on http://{}/metrics", addr);
+
+        let c = move || -> Result<_, _> {
+            for stream in listener.incoming() {
+                let result = stream
+                    .map_err(Error::from)
+                    .and_then(|stream| self.handle(stream));
+
+                // one bad client shouldn't stop the server
+                if let Err(e) = result {
+                    error!("metrics: {:?}", e);
+                }
+            }
+
+            Ok(())
+        };
+
+        Ok(thread::Builder::new()
+            .name("metrics".to_string())
+            .spawn(c)?)
+    }
+
+    fn handle(&self, mut stream: TcpStream) -> Result<(), Error> {
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+
+        // GET /metrics HTTP/1.1, then headers we don't care about
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+        }
+
+        let (status, content_type, body) = if Self::is_scrape(&request_line) {
+            ("200 OK", "text/plain; version=0.0.4", self.render()?)
+        } else {
+            ("404 Not Found", "text/plain", String::from("not found\n"))
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        )?;
+
+        Ok(())
+    }
+
+    // GET /metrics, ignoring any query string
+    fn is_scrape(request_line: &str) -> bool {
+        let mut parts = request_line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some("GET"), Some(target)) => target.split('?').next() == Some("/metrics"),
+            _ => false,
+        }
+    }
+
+    pub fn render(&self) -> Result<String, Error> {
+        let mut out = String::new();
+
+        // for the _sum and _count of the response time summary, which
+        // Prometheus expects to only ever go up
+        let alltime_times;
+
+        {
+            let alltime_stats = self.alltime_stats.lock().unwrap();
+            alltime_times = (
+                alltime_stats.global.timed_requests,
+                alltime_stats.global.time_total,
+            );
+
+            Self::header(
+                &mut out,
+                "requests_total",
+                "counter",
+                "Requests read since startup.",
+            );
+            for (class, counters) in STATUS_CLASSES
+                .iter()
+                .zip(alltime_stats.by_status_code.iter())
+            {
+                writeln!(
+                    out,
+                    "apachetop_requests_total{{status=\"{}\"}} {}",
+                    class, counters.requests
+                )?;
+            }
+
+            Self::header(
+                &mut out,
+                "bytes_total",
+                "counter",
+                "Response bytes sent since startup.",
+            );
+            for (class, counters) in STATUS_CLASSES
+                .iter()
+                .zip(alltime_stats.by_status_code.iter())
+            {
+                writeln!(
+                    out,
+                    "apachetop_bytes_total{{status=\"{}\"}} {}",
+                    class, counters.bytes
+                )?;
+            }
+        } // mutex on alltime_stats
+
+        let now = self.clock.now();
+        let mut ring_buffer = self.ring_buffer.lock().unwrap();
+        ring_buffer.cleanup(now)?;
+        let elapsed = ring_buffer.elapsed(now);
+
+        Self::header(
+            &mut out,
+            "window_requests",
+            "gauge",
+            "Requests in the recent window (--size).",
+        );
+        for (class, counters) in STATUS_CLASSES
+            .iter()
+            .zip(ring_buffer.stats.by_status_code.iter())
+        {
+            writeln!(
+                out,
+                "apachetop_window_requests{{status=\"{}\"}} {}",
+                class, counters.requests
+            )?;
+        }
+
+        Self::header(
+            &mut out,
+            "window_bytes",
+            "gauge",
+            "Response bytes in the recent window.",
+        );
+        for (class, counters) in STATUS_CLASSES
+            .iter()
+            .zip(ring_buffer.stats.by_status_code.iter())
+        {
+            writeln!(
+                out,
+                "apachetop_window_bytes{{status=\"{}\"}} {}",
+                class, counters.bytes
+            )?;
+        }
+
+        let global = &ring_buffer.stats.global;
+        Self::header(
+            &mut out,
+            "window_requests_per_second",
+            "gauge",
+            "Request rate over the recent window.",
+        );
+        writeln!(
+            out,
+            "apachetop_window_requests_per_second {}",
+            global.requests as f64 / elapsed
+        )?;
+        Self::header(
+            &mut out,
+            "window_bytes_per_second",
+            "gauge",
+            "Byte rate over the recent window.",
+        );
+        writeln!(
+            out,
+            "apachetop_window_bytes_per_second {}",
+            global.bytes as f64 / elapsed
+        )?;
+
+        let (timed_requests, time_total) = alltime_times;
+        if timed_requests > 0 {
+            Self::header(
+                &mut out,
+                "response_time_seconds",
+                "summary",
+                "Response times: quantiles over the recent window, sum and count since startup.",
+            );
+            Self::quantiles(&mut out, "apachetop_response_time_seconds", "", global)?;
+            writeln!(
+                out,
+                "apachetop_response_time_seconds_sum {}",
+                time_total as f64 / 1e6
+            )?;
+            writeln!(
+                out,
+                "apachetop_response_time_seconds_count {}",
+                timed_requests
+            )?;
+        }
+
+        let options = self.options.lock().unwrap();
+        let (group_by, top) = (options.group(), options.metrics_top);
+        drop(options);
+
+        if top > 0 {
+            if let Some(grouped) = &ring_buffer.grouped {
+                self.render_top(&mut out, grouped, group_by, top, elapsed)?;
+            }
+        }
+
+        Ok(out)
+    }
+
+    // Only the top rows, in the same order as Window, so the number of series
+    // stays bounded however many distinct keys there are.
+    fn render_top(
+        &self,
+        out: &mut String,
+        grouped: &GroupedStats,
+        group_by: GroupBy,
+        top: usize,
+        elapsed: f64,
+    ) -> Result<(), Error> {
+        use lazysort::SortedBy;
+
+        let rows: Vec<(&GroupKey, &RingBuffer)> = grouped
+            .iter()
+            .filter(|(_, v)| !v.buffer.is_empty())
+            .collect::<Vec<(&GroupKey, &RingBuffer)>>()
+            .into_iter()
+            .sorted_by(|a, b| b.1.cmp(a.1))
+            .take(top)
+            .collect();
+
+        let group = group_by.to_string().to_lowercase();
+        let label = |key: &GroupKey| {
+            format!(
+                "group=\"{}\",key=\"{}\"",
+                group,
+                Self::escape(&key.to_string())
+            )
+        };
+
+        Self::header(
+            out,
+            "top_requests",
+            "gauge",
+            "Requests in the recent window for the top rows of the current grouping.",
+        );
+        for (key, rb) in rows.iter() {
+            writeln!(
+                out,
+                "apachetop_top_requests{{{}}} {}",
+                label(key),
+                rb.stats.global.requests
+            )?;
+        }
+
+        Self::header(
+            out,
+            "top_bytes",
+            "gauge",
+            "Response bytes in the recent window for the top rows.",
+        );
+        for (key, rb) in rows.iter() {
+            writeln!(
+                out,
+                "apachetop_top_bytes{{{}}} {}",
+                label(key),
+                rb.stats.global.bytes
+            )?;
+        }
+
+        Self::header(
+            out,
+            "top_requests_per_second",
+            "gauge",
+            "Request rate over the recent window for the top rows.",
+        );
+        for (key, rb) in rows.iter() {
+            writeln!(
+                out,
+                "apachetop_top_requests_per_second{{{}}} {}",
+                label(key),
+                rb.stats.global.requests as f64 / elapsed
+            )?;
+        }
+
+        if rows
+            .iter()
+            .any(|(_, rb)| rb.stats.global.timed_requests > 0)
+        {
+            // a gauge rather than a summary, as there are no totals since
+            // startup for each row to give as its _sum and _count
+            Self::header(
+                out,
+                "top_response_time_seconds",
+                "gauge",
+                "Response time quantiles over the recent window for the top rows.",
+            );
+            for (key, rb) in rows.iter() {
+                let labels = format!("{},", label(key));
+                Self::quantiles(
+                    out,
+                    "apachetop_top_response_time_seconds",
+                    &labels,
+                    &rb.stats.global,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+        out.push_str(&format!("# HELP apachetop_{} {}\n", name, help));
+        out.push_str(&format!("# TYPE apachetop_{} {}\n", name, kind));
+    }
+
+    // p50, p95 and p99 response times; labels are any extra labels with a
+    // trailing comma
+    fn quantiles(
+        out: &mut String,
+        name: &str,
+        labels: &str,
+        counters: &Counters,
+    ) -> Result<(), Error> {
+        for q in [0.5, 0.95, 0.99].iter() {
+            if let Some(t) = counters.percentile_time(q * 100.0) {
+                writeln!(
+                    out,
+                    "{}{{{}quantile=\"{}\"}} {}",
+                    name,
+                    labels,
+                    q,
+                    t.as_secs_f64()
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // label values are in double quotes, with \, " and newlines escaped
+    fn escape(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::ManualClock;
+
+    use structopt::StructOpt;
+
+    #[test]
+    fn renders_counters_gauges_and_top_rows() {
+        let options = Options::from_iter(&["apachetop", "--metrics-top", "1"]);
+        let options = Arc::new(Mutex::new(options));
+
+        let now = chrono::DateTime::parse_from_rfc3339("2020-05-01T10:00:10Z").unwrap();
+        let clock = Arc::new(ManualClock::new(now.into()));
+
+        let alltime_stats = Arc::new(Mutex::new(Stats::new()));
+        let ring_buffer = Arc::new(Mutex::new(
+            RingBuffer::new(Arc::clone(&options), Some(0)).unwrap(),
+        ));
+
+        for (uri, status) in [("/a\"b", 200), ("/a\"b", 404), ("/c", 200)].iter() {
+            let mut request = Request::blank();
+            request.time = now;
+            request.uri = String::from(*uri);
+            request.status_code = *status;
+            request.size = 100;
+            request.response_time = Some(std::time::Duration::from_millis(250));
+
+            alltime_stats.lock().unwrap().add_request(&request);
+            ring_buffer.lock().unwrap().push(Arc::new(request)).unwrap();
+        }
+
+        let metrics = Metrics::new(options, clock, alltime_stats, ring_buffer);
+        let out = metrics.render().unwrap();
+
+        assert!(out.contains("# TYPE apachetop_requests_total counter\n"));
+        assert!(out.contains("apachetop_requests_total{status=\"2xx\"} 2\n"));
+        assert!(out.contains("apachetop_bytes_total{status=\"4xx\"} 100\n"));
+        assert!(out.contains("apachetop_window_requests{status=\"4xx\"} 1\n"));
+        assert!(out.contains("apachetop_response_time_seconds_count 3\n"));
+        assert!(out.contains("apachetop_response_time_seconds_sum 0.75\n"));
+
+        // only the busiest uri, with its quote escaped
+        assert!(out.contains("apachetop_top_requests{group=\"uri\",key=\"/a\\\"b\"} 2\n"));
+        assert!(!out.contains("key=\"/c\""));
+
+        // requests leaving the window don't take the summary's totals down
+        metrics.ring_buffer.lock().unwrap().pop();
+        let out = metrics.render().unwrap();
+        assert!(out.contains("apachetop_window_requests{status=\"2xx\"} 1\n"));
+        assert!(out.contains("apachetop_response_time_seconds_count 3\n"));
+        assert!(out.contains("apachetop_response_time_seconds_sum 0.75\n"));
+    }
+
+    #[test]
+    fn scrapes_ignore_query_strings() {
+        assert!(Metrics::is_scrape("GET /metrics HTTP/1.1\r\n"));
+        assert!(Metrics::is_scrape("GET /metrics?name[]=up HTTP/1.1\r\n"));
+        assert!(!Metrics::is_scrape("GET /metricsx HTTP/1.1\r\n"));
+        assert!(!Metrics::is_scrape("POST /metrics HTTP/1.1\r\n"));
+        assert!(!Metrics::is_scrape(""));
+    }
+}
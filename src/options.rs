@@ -11,10 +11,27 @@ pub struct Options {
 
     /// Main table sort order column
     ///
-    /// Can be either requests or size.
+    /// Can be any of the --columns, or requests, size, avgtime or maxtime.
+    /// Rows are in descending order unless followed by :asc, eg p95:asc.
     #[structopt(short, long, default_value = "requests")]
     pub order: Order, // see bottom of file
 
+    /// Columns of the main table, in order
+    ///
+    /// A comma separated list from: reqs, reqs/s, size, sz/s, sz/req
+    /// (average size), err% (4xx and 5xx responses as a percentage), 4xx,
+    /// 5xx, ips (unique client addresses), age (since the last request),
+    /// and avg, p50, p95, p99 and max response times.
+    ///
+    /// Response time columns are left out while there are no times to show.
+    /// Can also be changed while running by pressing c.
+    #[structopt(
+        long,
+        default_value = "reqs,reqs/s,size,sz/s,avg,p50,p95,p99,max",
+        require_delimiter = true
+    )]
+    pub columns: Vec<Column>,
+
     /// Group requests
     ///
     /// Determines how to aggregate statistics. By default the request URI
@@ -22,8 +39,12 @@ pub struct Options {
     ///
     /// Can be: ip, referer, status, uri, username
     ///
-    #[structopt(short, long, default_value = "uri")]
-    pub group: GroupBy,
+    /// A comma separated chain, eg uri,ip,status, also groups each row by
+    /// the next item, for drilling down. At most 3 levels are allowed, since
+    /// each level holds another reference to every request.
+    ///
+    #[structopt(short, long = "group", default_value = "uri", require_delimiter = true)]
+    pub group_chain: Vec<GroupBy>,
 
     /// Recent buffer size
     ///
@@ -36,6 +57,185 @@ pub struct Options {
     #[structopt(short = "s", long = "size", default_value = "1h")]
     pub buffer_size: String,
 
+    /// Only show requests matching this expression in the table
+    ///
+    /// eg 'status>=500 && uri~"^/api" && ip!=10.0.0.5'
+    ///
+    /// Numeric fields status, size and time (response time in milliseconds)
+    /// can be compared with == != < <= > >=. Text fields ip, method, uri,
+    /// referer, agent and username can be compared with == and !=, or
+    /// matched against a regex with ~ and !~. Combine comparisons with &&
+    /// and ||, negate with !, and group with parentheses.
+    ///
+    /// Can also be set while running by pressing f.
+    #[structopt(long)]
+    pub filter: Option<Filter>,
+
+    /// Only read requests from these addresses or networks
+    ///
+    /// A comma separated list, eg 10.0.0.0/8,192.168.1.1. This and the other
+    /// --include and --exclude options drop requests as they're read, so
+    /// they aren't counted at all; the number dropped is shown at the top.
+    /// Excludes win over includes.
+    #[structopt(long, require_delimiter = true, parse(try_from_str = Prefilter::parse_net))]
+    pub include_ip: Vec<ipnet::IpNet>,
+
+    /// Drop requests from these addresses or networks, eg 10.0.0.5,fd00::/8
+    #[structopt(long, require_delimiter = true, parse(try_from_str = Prefilter::parse_net))]
+    pub exclude_ip: Vec<ipnet::IpNet>,
+
+    /// Only read requests with a URI matching this regex
+    ///
+    /// May be specified multiple times, to read requests matching any of them.
+    #[structopt(long, number_of_values = 1)]
+    pub include_uri: Vec<regex::Regex>,
+
+    /// Drop requests with a URI matching this regex, eg '\.(css|js|png)$'
+    ///
+    /// May be specified multiple times.
+    #[structopt(long, number_of_values = 1)]
+    pub exclude_uri: Vec<regex::Regex>,
+
+    /// Only read requests with a user agent matching this regex
+    ///
+    /// May be specified multiple times, to read requests matching any of them.
+    #[structopt(long, number_of_values = 1)]
+    pub include_agent: Vec<regex::Regex>,
+
+    /// Drop requests with a user agent matching this regex, eg '(?i)healthcheck'
+    ///
+    /// May be specified multiple times.
+    #[structopt(long, number_of_values = 1)]
+    pub exclude_agent: Vec<regex::Regex>,
+
+    /// Only read requests with these methods, eg get,post
+    #[structopt(long, require_delimiter = true, parse(try_from_str = Prefilter::parse_method))]
+    pub include_method: Vec<crate::request::HttpMethod>,
+
+    /// Drop requests with these methods, eg head,options
+    #[structopt(long, require_delimiter = true, parse(try_from_str = Prefilter::parse_method))]
+    pub exclude_method: Vec<crate::request::HttpMethod>,
+
+    /// Apache LogFormat of the input logfile(s)
+    ///
+    /// Accepts a format string as written in httpd.conf, eg
+    /// '%v %h %l %u %t \"%r\" %>s %b %D', or one of the nicknames common,
+    /// combined or vhost_combined.
+    ///
+    /// Directives which apachetop doesn't use are still parsed, and kept as
+    /// extra fields. Without this, common and combined logs are understood.
+    #[structopt(long)]
+    pub log_format: Option<String>,
+
+    /// nginx log_format of the input logfile(s)
+    ///
+    /// Accepts the format string(s) from a log_format definition, eg
+    /// '$remote_addr [$time_local] "$request" $status $request_time', or
+    /// one of the nicknames combined or main.
+    ///
+    /// Unrecognised variables are kept as extra fields.
+    #[structopt(long, conflicts_with = "log-format")]
+    pub nginx_format: Option<String>,
+
+    /// Input logfile(s) contain one JSON object per line
+    ///
+    /// Fields are found using --json-field mappings.
+    #[structopt(long, conflicts_with_all = &["log-format", "nginx-format"])]
+    pub json: bool,
+
+    /// Map a field to its location in JSON input
+    ///
+    /// Given as NAME=PATH, eg status=.status or uri=.request.uri. Array
+    /// elements can be selected with [n]. May be specified multiple times.
+    ///
+    /// NAME can be: ip, identd, username, time, request, method, uri, query,
+    /// version, status, size, referer, user_agent, response_time,
+    /// upstream_response_time. Other names are kept as extra fields.
+    ///
+    /// Numeric times are taken as unix timestamps. Numeric durations are in
+    /// seconds unless the path ends in :ms, :us or :ns.
+    ///
+    /// Fields which aren't mapped are looked for at the top level under
+    /// their own name, eg .status.
+    #[structopt(long, number_of_values = 1, requires = "json")]
+    pub json_field: Vec<String>,
+
+    /// Read recent lines already in the logfile(s) at startup
+    ///
+    /// Like --size, an integer optionally suffixed by s, m, h or d. No suffix
+    /// reads the given number of lines from the end of each file; with a
+    /// suffix, lines logged within that time are read.
+    ///
+    /// Without this, only lines written after startup are shown.
+    #[structopt(long)]
+    pub backfill: Option<String>,
+
+    /// Replay the logfile(s) as if live, at the pace they were logged
+    ///
+    /// Requests are released by their logged time, sped up by the given
+    /// factor, eg 1, 10 or 60x. Rates and the --size window are then
+    /// calculated in log time. Files are read from the start unless
+    /// --backfill says otherwise.
+    #[structopt(long, parse(try_from_str = Options::parse_speed))]
+    pub replay: Option<f64>,
+
+    /// Batch mode: print plain text to stdout instead of the live display
+    ///
+    /// Every --interval, the overall stats and the top --top rows are
+    /// printed, as with top -b. Needs no terminal, so works under cron or
+    /// when redirected to a file.
+    #[structopt(short, long)]
+    pub batch: bool,
+
+    /// Number of times to print in batch mode before exiting
+    ///
+    /// Without this, batch mode runs until killed.
+    #[structopt(long, requires = "batch")]
+    pub iterations: Option<u64>,
+
+    /// Number of rows to print in batch mode
+    #[structopt(long, default_value = "20")]
+    pub top: usize,
+
+    /// Output format for batch mode
+    ///
+    /// Can be text, json (one object per interval) or csv (one row per
+    /// stats line, with a header first). Times are in microseconds.
+    /// Defaults to text.
+    #[structopt(long, requires = "batch")]
+    pub output: Option<OutputFormat>,
+
+    /// Serve Prometheus metrics on this address, eg 127.0.0.1:9118
+    ///
+    /// Scrape http://<address>/metrics for request and byte counters since
+    /// startup, and gauges for the recent window, by status class.
+    #[structopt(long)]
+    pub metrics: Option<std::net::SocketAddr>,
+
+    /// Also export metrics for this many top rows of the current grouping
+    ///
+    /// Caps the number of series however many distinct keys are seen.
+    /// 0 (the default) exports none.
+    #[structopt(long, default_value = "0")]
+    pub metrics_top: usize,
+
+    /// Send counters to a StatsD daemon at this address every interval
+    ///
+    /// Given as host:port, eg localhost:8125. Requests, bytes and requests
+    /// per status class since the last send are sent over UDP.
+    #[structopt(long)]
+    pub statsd: Option<String>,
+
+    /// Prefix for StatsD metric names
+    #[structopt(long, default_value = "apachetop")]
+    pub statsd_prefix: String,
+
+    /// Tag to add to StatsD metrics, DogStatsD style, eg env:prod
+    ///
+    /// May be specified multiple times.
+    #[structopt(long, number_of_values = 1)]
+    pub statsd_tag: Vec<String>,
+
     /// Output logfile (for debugging)
     #[structopt(short, long, default_value = "apachetop.log", parse(from_os_str))]
     pub debug: std::path::PathBuf,
@@ -43,8 +243,38 @@ pub struct Options {
     /// Logfile(s) to open
     ///
     /// May be specified multiple times, and works with pipes.
+    ///
+    /// Glob patterns (quoted, eg '/var/log/apache2/*-access.log') and
+    /// directories are also accepted. They're rescanned while running, and
+    /// matching files which appear later are read from the start.
+    ///
+    /// Compressed files (.gz, .zst or .bz2) named here are read once to the
+    /// end, so rotated logs can be loaded alongside the live one. Globs and
+    /// directories skip them, as they're usually logs already read before
+    /// being rotated and compressed.
     #[structopt(default_value = "/var/log/apache2/access.log", parse(from_os_str))]
     pub file: Vec<std::path::PathBuf>,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Analyse whole logfiles, print a report to stdout, and exit
+    ///
+    /// Shows overall stats, then the top rows for every grouping, ordered by
+    /// --order. Options for the live display (and --order, --log-format etc)
+    /// go before the report keyword.
+    Report {
+        /// Number of rows to show for each grouping
+        #[structopt(short = "n", long, default_value = "20")]
+        top: usize,
+
+        /// Logfile(s) to read; compressed files are fine
+        #[structopt(required = true, parse(from_os_str))]
+        file: Vec<std::path::PathBuf>,
+    },
 }
 
 impl Options {
@@ -55,35 +285,96 @@ impl Options {
             return Err(anyhow!("empty buffer size is invalid"));
         }
 
+        if r.columns.is_empty() {
+            return Err(anyhow!("at least one column is needed"));
+        }
+
+        if r.group_chain.len() > MAX_GROUP_DEPTH {
+            return Err(anyhow!(
+                "at most {} levels of grouping are supported",
+                MAX_GROUP_DEPTH
+            ));
+        }
+
         Ok(r)
     }
 
-    // Set the next Order in the sequence.
-    pub fn toggle_sort(&mut self) {
-        self.order = match self.order {
-            Order::Requests => Order::Size,
-            Order::Size => Order::Requests,
+    // Sort by the next of the columns shown (see Column::shown), in
+    // descending order.
+    pub fn toggle_sort(&mut self, shown: &[Column]) {
+        let next = match shown.iter().position(|c| *c == self.order.column) {
+            Some(i) => shown.get(i + 1),
+            None => None,
         };
+
+        if let Some(column) = next.or_else(|| shown.first()) {
+            self.order = Order {
+                column: *column,
+                ascending: false,
+            };
+        }
+    }
+
+    // Sort by the same column, in the other direction.
+    pub fn reverse_sort(&mut self) {
+        self.order.ascending = !self.order.ascending;
+    }
+
+    // The top level grouping, ie the main table's rows.
+    pub fn group(&self) -> GroupBy {
+        self.group_chain[0]
     }
 
-    // Set the next GroupBy in the sequence, and then return the new value.
+    // Set the next GroupBy in the sequence for the top level, and then
+    // return the new value.
     pub fn toggle_group(&mut self) -> GroupBy {
-        self.group = match self.group {
+        self.group_chain[0] = match self.group() {
             GroupBy::IpAddress => GroupBy::Referer,
             GroupBy::Referer => GroupBy::StatusCode,
             GroupBy::StatusCode => GroupBy::URI,
             GroupBy::URI => GroupBy::Username,
             GroupBy::Username => GroupBy::IpAddress,
         };
-        self.group
+        self.group()
     }
 
     // convert self.buffer_size into a tuple of (i64, ring_buffer::StorageType)
     pub fn storage_type(&self) -> Result<StorageType, Error> {
-        let suffix = self.buffer_size.chars().last().unwrap();
+        Self::parse_size(&self.buffer_size).context("failed to parse size")
+    }
+
+    // convert self.backfill into where to start reading existing logfiles
+    pub fn start_from(&self) -> Result<StartFrom, Error> {
+        let backfill = match self.backfill {
+            Some(ref b) => b,
+            None if self.replay.is_some() => return Ok(StartFrom::Beginning),
+            None => return Ok(StartFrom::End),
+        };
+
+        match Self::parse_size(backfill).context("failed to parse backfill")? {
+            StorageType::Requests(lines) => Ok(StartFrom::Lines(lines)),
+            StorageType::Timed(seconds) => Ok(StartFrom::Seconds(seconds)),
+        }
+    }
+
+    // parse a replay speed like 10 or 10x
+    fn parse_speed(input: &str) -> Result<f64, Error> {
+        let speed = input.trim_end_matches('x').parse::<f64>()?;
+        if !(speed > 0.0 && speed.is_finite()) {
+            return Err(anyhow!("replay speed must be positive"));
+        }
+        Ok(speed)
+    }
+
+    // parse a count with an optional time suffix, as used by --size and --backfill
+    fn parse_size(input: &str) -> Result<StorageType, Error> {
+        let suffix = input
+            .chars()
+            .last()
+            .ok_or_else(|| anyhow!("empty size is invalid"))?;
         let x: &[_] = &['s', 'm', 'h', 'd'];
-        let b = self.buffer_size.trim_end_matches(x);
-        let size = b.parse::<u64>().context("failed to parse size")?;
+        let b = input.trim_end_matches(x);
+        let size = b.parse::<u64>()?;
         match suffix {
             's' => Ok(StorageType::Timed(size)),
             'm' => Ok(StorageType::Timed(size * 60)),
@@ -94,23 +385,71 @@ impl Options {
     }
 }
 
-#[derive(Eq, PartialEq, Debug)]
-pub enum Order {
-    Requests,
-    Size,
+// levels in --group; each one adds a bucket per distinct key below the
+// level before, and another reference to every request
+pub const MAX_GROUP_DEPTH: usize = 3;
+
+// the column rows are sorted by, and which way
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct Order {
+    pub column: Column,
+    pub ascending: bool,
 }
 impl std::str::FromStr for Order {
     type Err = Error;
     fn from_str(input: &str) -> std::result::Result<Self, <Self as std::str::FromStr>::Err> {
-        // in actual fact, only a leading s is needed to order by size;
-        // anything else is taken as requests
-        match &input[0..1] {
-            "S" | "s" => Ok(Self::Size),
-            _ => Ok(Self::Requests),
+        let mut parts = input.splitn(2, ':');
+        let name = parts.next().unwrap_or_default();
+
+        let ascending = match parts.next() {
+            None | Some("desc") => false,
+            Some("asc") => true,
+            Some(x) => return Err(anyhow!("unknown sort direction {}", x)),
+        };
+
+        // names from before there were columns
+        let column = match name {
+            "requests" => Column::Requests,
+            "size" => Column::Size,
+            "avgtime" => Column::AvgTime,
+            "maxtime" => Column::MaxTime,
+            x => x.parse()?,
+        };
+
+        Ok(Self { column, ascending })
+    }
+}
+
+// as accepted by --order, eg reqs or p95:asc
+impl std::fmt::Display for Order {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.ascending {
+            write!(f, "{}:asc", self.column)
+        } else {
+            write!(f, "{}", self.column)
         }
     }
 }
 
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Csv,
+}
+impl std::str::FromStr for OutputFormat {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<Self, <Self as std::str::FromStr>::Err> {
+        match input {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            x => Err(anyhow!("unknown output format {}", x)),
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Eq, PartialEq, Debug, Clone, Copy)]
 pub enum GroupBy {
     IpAddress,
@@ -120,6 +459,17 @@ pub enum GroupBy {
     Username,
 }
 
+impl GroupBy {
+    // in the same order as toggle_group cycles through them
+    pub const ALL: [GroupBy; 5] = [
+        Self::IpAddress,
+        Self::Referer,
+        Self::StatusCode,
+        Self::URI,
+        Self::Username,
+    ];
+}
+
 // convert commandline args into a GroupBy object
 impl std::str::FromStr for GroupBy {
     type Err = Error;
@@ -128,8 +478,12 @@ impl std::str::FromStr for GroupBy {
             "ip" => Ok(Self::IpAddress),
             "referer" | "referrer" => Ok(Self::Referer),
             "status" => Ok(Self::StatusCode),
+            "uri" => Ok(Self::URI),
             "username" => Ok(Self::Username),
-            _ => Ok(Self::URI), // default and catchall
+            x => Err(anyhow!(
+                "unknown grouping {} (can be ip, referer, status, uri, username)",
+                x
+            )),
         }
     }
 }
@@ -0,0 +1,200 @@
+use crate::prelude::*;
+
+use crate::request::HttpMethod;
+
+use ipnet::IpNet;
+use regex::Regex;
+
+// Include and exclude rules from the command line, checked as each request
+// arrives, before it's counted anywhere. Unlike a Filter, which only hides
+// rows, requests dropped here are gone for good, so they take no memory and
+// don't skew the totals (eg health checks, static assets, internal IPs).
+//
+// A request is kept if it matches none of the exclude rules and, for each
+// kind of include rule given, at least one of those.
+#[derive(Debug)]
+pub struct Prefilter {
+    include_ips: Vec<IpNet>,
+    exclude_ips: Vec<IpNet>,
+
+    include_uris: Vec<Regex>,
+    exclude_uris: Vec<Regex>,
+
+    include_agents: Vec<Regex>,
+    exclude_agents: Vec<Regex>,
+
+    include_methods: Vec<HttpMethod>,
+    exclude_methods: Vec<HttpMethod>,
+}
+
+impl Prefilter {
+    pub fn from_options(options: &Options) -> Self {
+        Self {
+            include_ips: options.include_ip.clone(),
+            exclude_ips: options.exclude_ip.clone(),
+            include_uris: options.include_uri.clone(),
+            exclude_uris: options.exclude_uri.clone(),
+            include_agents: options.include_agent.clone(),
+            exclude_agents: options.exclude_agent.clone(),
+            include_methods: options.include_method.clone(),
+            exclude_methods: options.exclude_method.clone(),
+        }
+    }
+
+    pub fn keep(&self, request: &Request) -> bool {
+        let ip = |net: &IpNet| net.contains(&request.ip_address);
+        let uri = |re: &Regex| re.is_match(&request.uri);
+        let agent = |re: &Regex| re.is_match(&request.user_agent);
+        let method = |m: &HttpMethod| *m == request.method;
+
+        Self::allowed(&self.include_ips, &self.exclude_ips, ip)
+            && Self::allowed(&self.include_uris, &self.exclude_uris, uri)
+            && Self::allowed(&self.include_agents, &self.exclude_agents, agent)
+            && Self::allowed(&self.include_methods, &self.exclude_methods, method)
+    }
+
+    // no includes means everything is included
+    fn allowed<T>(include: &[T], exclude: &[T], matches: impl Fn(&T) -> bool) -> bool {
+        (include.is_empty() || include.iter().any(&matches)) && !exclude.iter().any(&matches)
+    }
+
+    // an address or network, eg 10.0.0.5 or 10.0.0.0/8
+    pub fn parse_net(input: &str) -> Result<IpNet, Error> {
+        match input.parse::<IpNet>() {
+            Ok(net) => Ok(net),
+            Err(_) => Ok(IpNet::from(input.parse::<IpAddr>().with_context(|| {
+                format!("expected an address or CIDR, found {}", input)
+            })?)),
+        }
+    }
+
+    // a method name in any case, eg get
+    pub fn parse_method(input: &str) -> Result<HttpMethod, Error> {
+        input
+            .to_uppercase()
+            .parse()
+            .with_context(|| format!("unknown method {}", input))
+    }
+}
+
+// the rules as options, eg "exclude-ip 10.0.0.0/8, include-method GET"
+impl std::fmt::Display for Prefilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn rules(name: &str, values: Vec<String>) -> Option<String> {
+            if values.is_empty() {
+                None
+            } else {
+                Some(format!("{} {}", name, values.join(",")))
+            }
+        }
+        fn strings<T: ToString>(values: &[T]) -> Vec<String> {
+            values.iter().map(|v| v.to_string()).collect()
+        }
+        let methods = |values: &[HttpMethod]| -> Vec<String> {
+            values.iter().map(|m| format!("{:?}", m)).collect()
+        };
+
+        let all = [
+            rules("include-ip", strings(&self.include_ips)),
+            rules("exclude-ip", strings(&self.exclude_ips)),
+            rules("include-uri", strings(&self.include_uris)),
+            rules("exclude-uri", strings(&self.exclude_uris)),
+            rules("include-agent", strings(&self.include_agents)),
+            rules("exclude-agent", strings(&self.exclude_agents)),
+            rules("include-method", methods(&self.include_methods)),
+            rules("exclude-method", methods(&self.exclude_methods)),
+        ];
+        let all: Vec<String> = all.iter().flatten().cloned().collect();
+
+        write!(f, "{}", all.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use structopt::StructOpt;
+
+    fn prefilter(args: &[&str]) -> Prefilter {
+        let args = std::iter::once("apachetop").chain(args.iter().copied());
+        Prefilter::from_options(&Options::from_iter(args))
+    }
+
+    fn request(ip: &str, method: HttpMethod, uri: &str, agent: &str) -> Request {
+        let mut request = Request::blank();
+        request.ip_address = ip.parse().unwrap();
+        request.method = method;
+        request.uri = String::from(uri);
+        request.user_agent = String::from(agent);
+        request
+    }
+
+    #[test]
+    fn keeps_everything_without_rules() {
+        let p = prefilter(&[]);
+        assert!(p.keep(&request("10.0.0.1", HttpMethod::GET, "/", "curl")));
+    }
+
+    #[test]
+    fn excludes_win_over_includes() {
+        let p = prefilter(&[
+            "--include-ip",
+            "10.0.0.0/8,192.168.1.1",
+            "--exclude-ip",
+            "10.0.0.5",
+            "--exclude-uri",
+            r"\.(css|js|png)$",
+            "--exclude-agent",
+            "(?i)healthcheck",
+            "--include-method",
+            "get,post",
+        ]);
+
+        assert!(p.keep(&request("10.1.2.3", HttpMethod::GET, "/", "curl")));
+        assert!(p.keep(&request("192.168.1.1", HttpMethod::POST, "/api", "curl")));
+
+        assert!(!p.keep(&request("10.0.0.5", HttpMethod::GET, "/", "curl")));
+        assert!(!p.keep(&request("192.168.1.2", HttpMethod::GET, "/", "curl")));
+        assert!(!p.keep(&request("10.1.2.3", HttpMethod::GET, "/site.css", "curl")));
+        assert!(!p.keep(&request(
+            "10.1.2.3",
+            HttpMethod::GET,
+            "/",
+            "ELB-HealthCheck"
+        )));
+        assert!(!p.keep(&request("10.1.2.3", HttpMethod::HEAD, "/", "curl")));
+    }
+
+    #[test]
+    fn describes_rules() {
+        assert_eq!(prefilter(&[]).to_string(), "");
+
+        let p = prefilter(&[
+            "--exclude-ip",
+            "10.0.0.0/8,10.0.0.5",
+            "--include-method",
+            "get",
+            "--exclude-uri",
+            "^/health$",
+        ]);
+        assert_eq!(
+            p.to_string(),
+            "exclude-ip 10.0.0.0/8,10.0.0.5/32, exclude-uri ^/health$, include-method GET"
+        );
+    }
+
+    #[test]
+    fn parses_addresses_and_networks() {
+        assert_eq!(
+            Prefilter::parse_net("10.0.0.5").unwrap().to_string(),
+            "10.0.0.5/32"
+        );
+        assert_eq!(
+            Prefilter::parse_net("2001:db8::/32").unwrap().to_string(),
+            "2001:db8::/32"
+        );
+        assert!(Prefilter::parse_net("10.0.0.0/33").is_err());
+        assert!(Prefilter::parse_method("TRACE").is_err());
+    }
+}
@@ -1,6 +1,6 @@
 pub use anyhow::{anyhow, Context, Error};
 
-pub use log::{debug, error, info, trace, warn};
+pub use log::{debug, error, info, warn};
 
 pub use std::collections::{HashMap, VecDeque};
 pub use std::net::IpAddr;
@@ -8,10 +8,20 @@ pub use std::sync::{Arc, Mutex};
 pub use std::thread;
 
 pub use crate::app::App;
+pub use crate::batch::Batch;
+pub use crate::clock::Clock;
+pub use crate::columns::Column;
+pub use crate::filter::Filter;
 pub use crate::grouped_stats::{GroupKey, GroupedStats};
-pub use crate::logfile::Logfile;
-pub use crate::options::{GroupBy, Options};
+pub use crate::inputs::Inputs;
+pub use crate::log_format::LogFormat;
+pub use crate::logfile::{Logfile, StartFrom};
+pub use crate::metrics::Metrics;
+pub use crate::options::{Command, GroupBy, Options, OutputFormat};
+pub use crate::prefilter::Prefilter;
+pub use crate::report::Report;
 pub use crate::request::Request;
 pub use crate::ring_buffer::{RingBuffer, StorageType};
 pub use crate::stats::Stats;
+pub use crate::statsd::Statsd;
 pub use crate::window::Window;
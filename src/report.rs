@@ -0,0 +1,294 @@
+use crate::prelude::*;
+
+use crossbeam_channel::bounded;
+
+// Offline report mode: read whole logfiles, then print the top groups for
+// every GroupBy to stdout and exit. No Window, and nothing is followed.
+pub struct Report {
+    options: Arc<Mutex<Options>>,
+
+    files: Vec<std::path::PathBuf>,
+    top: usize,
+
+    stats: Stats,
+    grouped: Vec<GroupedStats>,
+
+    // --include and --exclude rules, and how many requests they've dropped
+    prefilter: Prefilter,
+    dropped: u64,
+
+    // span of request times seen, for per-second rates
+    first: Option<chrono::DateTime<chrono::FixedOffset>>,
+    last: Option<chrono::DateTime<chrono::FixedOffset>>,
+}
+
+impl Report {
+    pub fn new(options: Options, files: Vec<std::path::PathBuf>, top: usize) -> Self {
+        let prefilter = Prefilter::from_options(&options);
+        let options = Arc::new(Mutex::new(options));
+
+        let grouped = GroupBy::ALL
+            .iter()
+            .map(|group_by| GroupedStats::new(Arc::clone(&options), *group_by, None))
+            .collect();
+
+        Self {
+            options,
+            files,
+            top,
+            stats: Stats::new(),
+            grouped,
+            prefilter,
+            dropped: 0,
+            first: None,
+            last: None,
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        let log_format = LogFormat::from_options(&self.options.lock().unwrap())?;
+
+        // requests are added as they're read, so only a few are held at once
+        // however big the files are
+        let (request_tx, request_rx) = bounded(10_000);
+
+        let files = self.files.clone();
+        let c = move || -> Result<_, Error> {
+            for path in files {
+                let mut br = Logfile::open_to_read(&path)?;
+
+                Logfile::read_lines(&mut br, &log_format, &request_tx)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+            }
+
+            Ok(())
+        };
+        let reader = thread::Builder::new()
+            .name("report_reader".to_string())
+            .spawn(c)?;
+
+        for request in request_rx {
+            self.add(request)?;
+        }
+
+        reader
+            .join()
+            .map_err(|_| anyhow!("report reader thread panicked"))??;
+
+        print!("{}", self);
+
+        Ok(())
+    }
+
+    fn add(&mut self, request: Request) -> Result<(), Error> {
+        if !self.prefilter.keep(&request) {
+            self.dropped += 1;
+            return Ok(());
+        }
+
+        self.stats.add_request(&request);
+
+        if self.first.is_none_or(|t| request.time < t) {
+            self.first = Some(request.time);
+        }
+        if self.last.is_none_or(|t| request.time > t) {
+            self.last = Some(request.time);
+        }
+
+        let request = Arc::new(request);
+        for grouped in self.grouped.iter_mut() {
+            grouped.add(Arc::clone(&request))?;
+        }
+
+        Ok(())
+    }
+
+    fn elapsed(&self) -> f64 {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => std::cmp::max((last - first).num_seconds(), 1) as f64,
+            _ => 1.0,
+        }
+    }
+
+    fn fmt_summary(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let global = &self.stats.global;
+        let elapsed = self.elapsed();
+
+        if let (Some(first), Some(last)) = (self.first, self.last) {
+            writeln!(
+                f,
+                "{} to {}",
+                first.format("%d/%b/%Y:%T %z"),
+                last.format("%d/%b/%Y:%T %z")
+            )?;
+        }
+
+        write!(
+            f,
+            "{} reqs ({:.2}/sec) {} ({}/sec) {}/req",
+            global.requests,
+            global.requests as f64 / elapsed,
+            Window::humansize(global.bytes as f64).trim(),
+            Window::humansize(global.bytes as f64 / elapsed).trim(),
+            Window::humansize(global.bytes as f64 / std::cmp::max(global.requests, 1) as f64)
+                .trim(),
+        )?;
+        if self.dropped > 0 {
+            write!(f, ", dropped: {}", self.dropped)?;
+        }
+        writeln!(f)?;
+
+        for (i, class) in ["1xx", "2xx", "3xx", "4xx", "5xx"].iter().enumerate() {
+            let requests = self.stats.by_status_code[i + 1].requests;
+            let pct = 100.0 * requests as f64 / std::cmp::max(global.requests, 1) as f64;
+            write!(f, "{}: {} ({:.1}%) ", class, requests, pct)?;
+        }
+        writeln!(f)?;
+
+        if global.timed_requests > 0 {
+            writeln!(
+                f,
+                "Time: min {} avg {} p50 {} p95 {} p99 {} max {}",
+                Window::humantime(global.min_time()),
+                Window::humantime(global.avg_time()),
+                Window::humantime(global.percentile_time(50.0)),
+                Window::humantime(global.percentile_time(95.0)),
+                Window::humantime(global.percentile_time(99.0)),
+                Window::humantime(global.max_time()),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn fmt_grouped(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        grouped: &GroupedStats,
+        group_by: GroupBy,
+    ) -> std::fmt::Result {
+        use lazysort::SortedBy;
+
+        let elapsed = self.elapsed();
+        let total = std::cmp::max(self.stats.global.requests, 1) as f64;
+
+        writeln!(f)?;
+        writeln!(
+            f,
+            "  REQS   REQ%  REQS/S    SIZE    AVG    P95    MAX {}",
+            group_by
+        )?;
+
+        // same ordering as the live table, see impl Ord for RingBuffer
+        for (key, rb) in grouped
+            .iter()
+            .collect::<Vec<(&GroupKey, &RingBuffer)>>()
+            .iter()
+            .sorted_by(|a, b| b.1.cmp(a.1))
+            .take(self.top)
+        {
+            let global = &rb.stats.global;
+            writeln!(
+                f,
+                "{:6} {:5.1}% {:7.2} {:>7} {:>6} {:>6} {:>6} {}",
+                global.requests,
+                100.0 * global.requests as f64 / total,
+                global.requests as f64 / elapsed,
+                Window::humansize(global.bytes as f64),
+                Window::humantime(global.avg_time()),
+                Window::humantime(global.percentile_time(95.0)),
+                Window::humantime(global.max_time()),
+                key
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_summary(f)?;
+
+        for (grouped, group_by) in self.grouped.iter().zip(GroupBy::ALL.iter()) {
+            self.fmt_grouped(f, grouped, *group_by)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use structopt::StructOpt;
+
+    fn file(name: &str, lines: &[&str]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("apachetop-{}-{}", std::process::id(), name));
+        std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_every_file_then_summarises() {
+        let files = vec![
+            file(
+                "report-1.log",
+                &[
+                    r#"10.0.0.1 - - [01/May/2020:10:00:00 +0000] "GET /a HTTP/1.1" 200 100 "-" "x""#,
+                    "garbage",
+                    r#"10.0.0.2 - - [01/May/2020:10:00:10 +0000] "GET /b HTTP/1.1" 404 50 "-" "x""#,
+                ],
+            ),
+            file(
+                "report-2.log",
+                &[r#"10.0.0.1 - - [01/May/2020:10:01:40 +0000] "GET /a HTTP/1.1" 200 100 "-" "x""#],
+            ),
+        ];
+
+        let options = Options::from_iter(&["apachetop"]);
+        let mut report = Report::new(options, files.clone(), 10);
+        report.run().unwrap();
+
+        assert_eq!(report.stats.global.requests, 3);
+        assert_eq!(report.stats.global.bytes, 250);
+        assert_eq!(report.elapsed(), 100.0);
+
+        let text = report.to_string();
+        assert!(text.starts_with("01/May/2020:10:00:00 +0000 to 01/May/2020:10:01:40 +0000\n"));
+        assert!(text.contains("4xx: 1 (33.3%)"));
+
+        for path in files {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn prefilters_drop_requests() {
+        let files = vec![file(
+            "report-prefilter.log",
+            &[
+                r#"10.0.0.1 - - [01/May/2020:10:00:00 +0000] "GET /a HTTP/1.1" 200 100 "-" "x""#,
+                r#"10.0.0.1 - - [01/May/2020:10:00:01 +0000] "GET /a.css HTTP/1.1" 200 100 "-" "x""#,
+                r#"10.0.0.2 - - [01/May/2020:10:00:02 +0000] "GET /b HTTP/1.1" 200 100 "-" "x""#,
+            ],
+        )];
+
+        let options = Options::from_iter(&[
+            "apachetop",
+            "--exclude-uri",
+            r"\.css$",
+            "--exclude-ip",
+            "10.0.0.2",
+        ]);
+        let mut report = Report::new(options, files.clone(), 10);
+        report.run().unwrap();
+
+        assert_eq!(report.stats.global.requests, 1);
+        assert_eq!(report.dropped, 2);
+        assert!(report.to_string().contains("100B/req, dropped: 2\n"));
+
+        std::fs::remove_file(&files[0]).unwrap();
+    }
+}
@@ -4,7 +4,8 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use strum_macros::EnumString;
 
-#[derive(EnumString, Debug, Eq, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(EnumString, Debug, Eq, PartialEq, Clone, Copy)]
 pub enum HttpMethod {
     CONNECT,
     DELETE,
@@ -43,6 +44,15 @@ pub struct Request {
     pub size: i64,
     pub referer: String, // sic
     pub user_agent: String,
+
+    // time taken to serve the request, and the part of that spent waiting on
+    // upstream servers, where the log format records them
+    pub response_time: Option<std::time::Duration>,
+    pub upstream_response_time: Option<std::time::Duration>,
+
+    // anything parsed from a custom log format which doesn't map onto one of
+    // the fields above, keyed by the directive that produced it (eg "v" for %v)
+    pub extra: HashMap<String, String>,
 }
 
 impl Request {
@@ -79,9 +89,74 @@ impl Request {
                 .unwrap_or_default(),
             referer: String::from(r.get(10).unwrap().as_str()),
             user_agent: String::from(r.get(11).unwrap().as_str()),
+            response_time: None,
+            upstream_response_time: None,
+            extra: HashMap::new(),
         })
     }
 
+    // A Request with every field set to a neutral default, for parsers which
+    // fill it in one directive at a time (see LogFormat). Time defaults to now,
+    // for formats which don't log it at all.
+    pub fn blank() -> Self {
+        Self {
+            ip_address: IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
+            identd: None,
+            username: None,
+            time: chrono::Local::now().into(),
+            method: HttpMethod::GET,
+            uri: String::new(),
+            http_version: HttpVersion::Http1_1,
+            status_code: 0,
+            size: 0,
+            referer: String::new(),
+            user_agent: String::new(),
+            response_time: None,
+            upstream_response_time: None,
+            extra: HashMap::new(),
+        }
+    }
+
+    // Split a request line such as "GET /foo HTTP/1.1" into method, uri
+    // and http_version. The uri and version are optional, as in CLF_RE.
+    pub fn set_request_line(&mut self, input: &str) -> Result<(), Error> {
+        let mut parts = input.split_whitespace();
+
+        self.method = parts
+            .next()
+            .ok_or_else(|| anyhow!("empty request line"))?
+            .parse()?;
+
+        if let Some(uri) = parts.next() {
+            self.uri = String::from(uri);
+        }
+
+        if let Some(http_version) = parts.next() {
+            self.http_version = http_version.parse()?;
+        }
+
+        Ok(())
+    }
+
+    // Rough size including heap allocations, for memory accounting.
+    pub fn memory(&self) -> usize {
+        let strings = [
+            self.identd.as_ref().map_or(0, String::capacity),
+            self.username.as_ref().map_or(0, String::capacity),
+            self.uri.capacity(),
+            self.referer.capacity(),
+            self.user_agent.capacity(),
+        ];
+
+        let extra: usize = self
+            .extra
+            .iter()
+            .map(|(k, v)| std::mem::size_of::<(String, String)>() + k.capacity() + v.capacity())
+            .sum();
+
+        std::mem::size_of::<Self>() + strings.iter().sum::<usize>() + extra
+    }
+
     pub fn group_key(&self, group_by: GroupBy) -> GroupKey {
         match group_by {
             GroupBy::IpAddress => GroupKey::IpAddress(self.ip_address),
@@ -95,7 +170,7 @@ impl Request {
         }
     }
 
-    fn parse(input: &str) -> Result<regex::Captures, Error> {
+    fn parse(input: &str) -> Result<regex::Captures<'_>, Error> {
         lazy_static! {
             static ref CLF_RE: Regex = Regex::new(r#"^(\S+) (\S+) (\S+) \[([\w:/]+\s[+\-]\d{4})\] "(\S+)\s?(\S+)?\s?(\S+)?" (\d{3}|-) (\d+|-)\s?"?([^"]*)"?\s?"?([^"]*)?"?$"#).unwrap();
         }
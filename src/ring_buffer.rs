@@ -9,31 +9,62 @@ pub struct RingBuffer {
     pub buffer: VecDeque<Arc<Request>>,
 
     pub grouped: Option<GroupedStats>,
+
+    // only requests matching this are added to grouped; from --filter, and
+    // only set on the main buffer, as nested ones get already filtered requests
+    filter: Option<Filter>,
+
+    // requests which arrived after one with a later time, since startup
+    pub late: u64,
+
+    // requests thrown away by the Prefilter before reaching here, since
+    // startup; only counted on the main buffer
+    pub dropped: u64,
+
+    // requests held from each client address, for unique_ips
+    ips: HashMap<IpAddr, usize>,
+
+    // see request_memory; kept up to date as requests come and go
+    request_bytes: usize,
 }
 
 impl RingBuffer {
-    pub fn new(options: Arc<Mutex<Options>>, with_grouped: bool) -> Result<Self, Error> {
-        let size = Self::size_from_options(&options)?;
-
-        // This is false when creating nested RingBuffers inside other GroupedStats, ie
-        // we only support one level of grouping right now.
-        //
-        // Eventually it may be true but we'll need a circuit breaker to stop recursing
-        // forever.
-        let grouped = if with_grouped {
-            // TODO: allow setting this per level in UI
-            let group_by = options.lock().unwrap().group;
-
-            Some(GroupedStats::new(Arc::clone(&options), group_by))
+    // level is the position in the --group chain to group this buffer's
+    // requests by, or None for no grouping. The main buffer is level 0; the
+    // buckets inside its GroupedStats are level 1, and so on until the
+    // chain runs out.
+    pub fn new(options: Arc<Mutex<Options>>, level: Option<usize>) -> Result<Self, Error> {
+        // nested buckets only ever hold a share of the requests, so let them
+        // grow as needed rather than each preallocating the whole buffer size
+        let size = if level == Some(0) {
+            Self::size_from_options(&options)?
+        } else {
+            0
+        };
+
+        let o = options.lock().unwrap();
+        let group_by = level.and_then(|i| o.group_chain.get(i).copied());
+        let filter = if level == Some(0) {
+            o.filter.clone()
         } else {
             None
         };
+        drop(o);
+
+        let grouped = group_by.map(|group_by| {
+            GroupedStats::new(Arc::clone(&options), group_by, level.map(|i| i + 1))
+        });
 
         Ok(Self {
             stats: Stats::new(),
             options,
             buffer: VecDeque::<Arc<Request>>::with_capacity(size as usize),
             grouped,
+            filter,
+            late: 0,
+            dropped: 0,
+            ips: HashMap::new(),
+            request_bytes: 0,
         })
     }
 
@@ -41,36 +72,85 @@ impl RingBuffer {
         self.buffer.front()
     }
 
+    // Seconds from the oldest request held until now, for per-second rates.
+    // Never less than 1, to avoid dividing by zero.
+    pub fn elapsed(&self, now: chrono::DateTime<chrono::Utc>) -> f64 {
+        match self.first() {
+            Some(f) => {
+                let first = chrono::DateTime::<chrono::Utc>::from(f.time);
+                std::cmp::max((now - first).num_seconds(), 1) as f64
+            }
+            None => 1.0,
+        }
+    }
+
     // Clear out self.grouped, if we have one, and repopulate it according
     // to the new passed-in GroupBy.
     //
     // This is used when the grouping key changes.
     pub fn regroup(&mut self, group_by: GroupBy) -> Option<Result<(), Error>> {
-        let grouped = self.grouped.as_mut()?;
+        self.grouped.as_mut()?.group_by(group_by);
+
+        Some(self.rebuild_grouped())
+    }
 
-        grouped.group_by(group_by);
+    // As regroup, but for a new filter (or none), which changes which of
+    // the requests belong in self.grouped.
+    pub fn refilter(&mut self, filter: Option<Filter>) -> Option<Result<(), Error>> {
+        self.filter = filter;
+        self.grouped.as_mut()?.clear();
 
-        for request in self.buffer.iter() {
-            if let Err(e) = grouped.add(Arc::clone(&request)) {
-                return Some(Err(e));
+        Some(self.rebuild_grouped())
+    }
+
+    fn rebuild_grouped(&mut self) -> Result<(), Error> {
+        if let Some(ref mut grouped) = self.grouped {
+            for request in self.buffer.iter() {
+                if self.filter.as_ref().is_none_or(|f| f.matches(request)) {
+                    grouped.add(Arc::clone(request))?;
+                }
             }
         }
 
-        Some(Ok(()))
+        Ok(())
     }
 
+    fn matches(&self, request: &Request) -> bool {
+        self.filter.as_ref().is_none_or(|f| f.matches(request))
+    }
+
+    // Requests mostly arrive in time order, but not always: several Logfile
+    // readers feed the same channel, and Apache stamps lines with the time a
+    // request started but writes them when it finishes. The buffer is kept
+    // sorted by time regardless, so the front is always the oldest.
     pub fn push(&mut self, request: Arc<Request>) -> Result<(), Error> {
         self.stats.add_request(&request);
-        self.buffer.push_back(request.clone());
+        *self.ips.entry(request.ip_address).or_insert(0) += 1;
+        self.request_bytes += request.memory();
 
-        if let Some(ref mut grouped) = self.grouped {
-            grouped.add(request)?;
+        match self.buffer.back() {
+            Some(last) if request.time < last.time => {
+                self.late += 1;
+
+                // after any requests with the same time, to keep arrival order
+                let i = self.buffer.partition_point(|r| r.time <= request.time);
+                self.buffer.insert(i, Arc::clone(&request));
+            }
+            _ => self.buffer.push_back(Arc::clone(&request)),
+        }
+
+        if self.matches(&request) {
+            if let Some(ref mut grouped) = self.grouped {
+                grouped.add(request)?;
+            }
         }
 
         Ok(())
     }
 
-    pub fn cleanup(&mut self) -> Result<(), Error> {
+    // Evict requests beyond the buffer size; for a timed buffer, that's
+    // anything older than now (which is log time when replaying).
+    pub fn cleanup(&mut self, now: chrono::DateTime<chrono::Utc>) -> Result<(), Error> {
         let o = self.options.lock().unwrap();
         let s = o.storage_type()?;
         drop(o);
@@ -85,7 +165,7 @@ impl RingBuffer {
                 // check if first hits are older than size (seconds)
                 while let Some(f) = self.first() {
                     let first = chrono::DateTime::<chrono::Utc>::from(f.time);
-                    let age = chrono::Utc::now() - first;
+                    let age = now - first;
 
                     if (age.num_seconds() as u64) < seconds {
                         break;
@@ -103,10 +183,14 @@ impl RingBuffer {
         match self.buffer.pop_front() {
             Some(request) => {
                 self.stats.remove_request(&request);
+                self.remove_ip(request.ip_address);
+                self.request_bytes -= request.memory();
 
                 // remove from grouped stats as well, if we have any
-                if let Some(ref mut grouped) = self.grouped {
-                    grouped.remove(Arc::clone(&request));
+                if self.matches(&request) {
+                    if let Some(ref mut grouped) = self.grouped {
+                        grouped.remove(Arc::clone(&request));
+                    }
                 }
 
                 Some(request)
@@ -115,6 +199,56 @@ impl RingBuffer {
         }
     }
 
+    // Remove a particular request, wherever it is in the buffer. Used for
+    // nested buckets, as requests are evicted from the main RingBuffer.
+    pub fn remove(&mut self, request: &Arc<Request>) -> Option<Arc<Request>> {
+        let i = self.buffer.iter().position(|r| Arc::ptr_eq(r, request))?;
+        let request = self.buffer.remove(i)?;
+
+        self.stats.remove_request(&request);
+        self.remove_ip(request.ip_address);
+        self.request_bytes -= request.memory();
+        if let Some(ref mut grouped) = self.grouped {
+            grouped.remove(Arc::clone(&request));
+        }
+
+        Some(request)
+    }
+
+    // Rough heap usage of this buffer and any groups nested inside it, not
+    // counting the Requests, which are shared by every level. Cheap enough
+    // to call on every redraw, as the nested part is a running total.
+    pub fn memory(&self) -> usize {
+        let mut bytes = self.buffer.capacity() * std::mem::size_of::<Arc<Request>>();
+        bytes += self.ips.capacity() * std::mem::size_of::<(IpAddr, usize)>();
+        bytes += self.stats.memory();
+
+        if let Some(ref grouped) = self.grouped {
+            bytes += grouped.memory();
+        }
+
+        bytes
+    }
+
+    // Distinct client addresses among the requests held, for the ips column.
+    pub fn unique_ips(&self) -> usize {
+        self.ips.len()
+    }
+
+    fn remove_ip(&mut self, ip: IpAddr) {
+        if let Some(count) = self.ips.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.ips.remove(&ip);
+            }
+        }
+    }
+
+    // Rough size of the Requests held, on top of memory().
+    pub fn request_memory(&self) -> usize {
+        self.request_bytes
+    }
+
     fn size_from_options(options: &Arc<Mutex<Options>>) -> Result<u64, Error> {
         let o = options.lock().unwrap();
         Ok(match o.storage_type()? {
@@ -129,16 +263,23 @@ impl Ord for RingBuffer {
     // ordering defined in options.order (also stored in each RingBuffer)
     //
     // this is how we order the rows in the main display table.
+    //
+    // Rows are shown greatest first, so an ascending order is reversed here,
+    // except that rows without a value (eg no times) always go last.
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        use super::options::Order;
+        use std::cmp::Ordering;
 
-        let this = &self.stats.global;
-        let other = &other.stats.global;
+        let order = self.options.lock().unwrap().order;
 
-        let options = self.options.lock().unwrap();
-        match options.order {
-            Order::Requests => this.requests.cmp(&other.requests),
-            Order::Size => this.bytes.cmp(&other.bytes),
+        match (
+            order.column.sort_value(self),
+            order.column.sort_value(other),
+        ) {
+            (Some(this), Some(other)) if order.ascending => other.total_cmp(&this),
+            (Some(this), Some(other)) => this.total_cmp(&other),
+            (Some(_), None) => Ordering::Greater,
+            (None, Some(_)) => Ordering::Less,
+            (None, None) => Ordering::Equal,
         }
     }
 }
@@ -164,3 +305,342 @@ pub enum StorageType {
     Requests(u64),
     Timed(u64),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::ManualClock;
+    use crate::options::Order;
+
+    use chrono::{DateTime, Utc};
+    use structopt::StructOpt;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().into()
+    }
+
+    fn ring_buffer(size: &str) -> RingBuffer {
+        grouped_ring_buffer(size, "uri")
+    }
+
+    fn grouped_ring_buffer(size: &str, group: &str) -> RingBuffer {
+        let options = Options::from_iter(&["apachetop", "--size", size, "--group", group]);
+        RingBuffer::new(Arc::new(Mutex::new(options)), Some(0)).unwrap()
+    }
+
+    fn request(time: DateTime<Utc>, uri: &str) -> Arc<Request> {
+        let mut request = Request::blank();
+        request.time = time.into();
+        request.uri = String::from(uri);
+        request.size = 1000;
+        Arc::new(request)
+    }
+
+    #[test]
+    fn timed_cleanup_evicts_requests_older_than_size() {
+        let clock = ManualClock::new(at("2020-05-01T10:00:00Z"));
+        let mut rb = ring_buffer("60s");
+
+        // one request every 10 seconds for a minute and a half
+        for i in 0..10 {
+            rb.push(request(clock.now(), "/a")).unwrap();
+            if i < 9 {
+                clock.advance(chrono::Duration::seconds(10));
+            }
+        }
+
+        // now is 10:01:30, so 10:00:30 onwards are kept
+        rb.cleanup(clock.now()).unwrap();
+        assert_eq!(rb.buffer.len(), 6);
+        assert_eq!(rb.stats.global.requests, 6);
+        assert_eq!(
+            DateTime::<Utc>::from(rb.first().unwrap().time),
+            at("2020-05-01T10:00:40Z")
+        );
+
+        // grouped stats lose the evicted requests too
+        let grouped = rb.grouped.as_ref().unwrap();
+        let (_, bucket) = grouped.iter().next().unwrap();
+        assert_eq!(bucket.stats.global.requests, 6);
+
+        clock.advance(chrono::Duration::minutes(5));
+        rb.cleanup(clock.now()).unwrap();
+        assert!(rb.buffer.is_empty());
+        assert_eq!(rb.stats.global.requests, 0);
+    }
+
+    #[test]
+    fn request_count_cleanup_ignores_the_clock() {
+        let clock = ManualClock::new(at("2020-05-01T10:00:00Z"));
+        let mut rb = ring_buffer("3");
+
+        for _ in 0..5 {
+            rb.push(request(clock.now(), "/a")).unwrap();
+        }
+
+        clock.advance(chrono::Duration::days(7));
+        rb.cleanup(clock.now()).unwrap();
+        assert_eq!(rb.buffer.len(), 3);
+    }
+
+    #[test]
+    fn per_second_rates_use_time_since_oldest_request() {
+        let clock = ManualClock::new(at("2020-05-01T10:00:00Z"));
+        let mut rb = ring_buffer("1h");
+
+        assert_eq!(rb.elapsed(clock.now()), 1.0);
+
+        // 120 requests over two minutes
+        for _ in 0..120 {
+            rb.push(request(clock.now(), "/a")).unwrap();
+            clock.advance(chrono::Duration::seconds(1));
+        }
+
+        let elapsed = rb.elapsed(clock.now());
+        assert_eq!(elapsed, 120.0);
+        assert_eq!(rb.stats.global.requests as f64 / elapsed, 1.0);
+        assert_eq!(rb.stats.global.bytes as f64 / elapsed, 1000.0);
+
+        // a burst in the same second as the first request still has a rate
+        let mut rb = ring_buffer("1h");
+        rb.push(request(clock.now(), "/a")).unwrap();
+        rb.push(request(clock.now(), "/a")).unwrap();
+        assert_eq!(rb.elapsed(clock.now()), 1.0);
+    }
+
+    #[test]
+    fn out_of_order_requests_are_evicted_by_time() {
+        let clock = ManualClock::new(at("2020-05-01T10:01:00Z"));
+        let mut rb = ring_buffer("60s");
+
+        rb.push(request(at("2020-05-01T10:00:30Z"), "/a")).unwrap();
+        rb.push(request(at("2020-05-01T10:00:50Z"), "/b")).unwrap();
+
+        // arrives last, but is the oldest, eg from a slower logfile
+        rb.push(request(at("2020-05-01T10:00:10Z"), "/c")).unwrap();
+        rb.push(request(at("2020-05-01T10:00:40Z"), "/a")).unwrap();
+        assert_eq!(rb.late, 2);
+
+        let times: Vec<_> = rb.buffer.iter().map(|r| r.time).collect();
+        let mut sorted = times.clone();
+        sorted.sort();
+        assert_eq!(times, sorted);
+
+        // 10:00:10 and 10:00:30 are over a minute old
+        clock.advance(chrono::Duration::seconds(35));
+        rb.cleanup(clock.now()).unwrap();
+
+        let uris: Vec<_> = rb.buffer.iter().map(|r| r.uri.as_str()).collect();
+        assert_eq!(uris, vec!["/a", "/b"]);
+
+        // and each group lost the right request
+        let grouped = rb.grouped.as_ref().unwrap();
+        let mut groups: Vec<_> = grouped
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.stats.global.requests, v.buffer[0].time))
+            .collect();
+        groups.sort();
+        assert_eq!(
+            groups,
+            vec![
+                (String::from("/a"), 1, at("2020-05-01T10:00:40Z").into()),
+                (String::from("/b"), 1, at("2020-05-01T10:00:50Z").into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_groups_follow_the_chain() {
+        let clock = ManualClock::new(at("2020-05-01T10:00:00Z"));
+        let mut rb = grouped_ring_buffer("60s", "uri,ip,status");
+
+        for (uri, ip, status) in [
+            ("/a", "10.0.0.1", 200),
+            ("/a", "10.0.0.1", 404),
+            ("/a", "10.0.0.2", 200),
+            ("/b", "10.0.0.1", 200),
+        ]
+        .iter()
+        {
+            let mut request = Request::blank();
+            request.time = clock.now().into();
+            request.uri = String::from(*uri);
+            request.ip_address = ip.parse().unwrap();
+            request.status_code = *status;
+            rb.push(Arc::new(request)).unwrap();
+            clock.advance(chrono::Duration::seconds(10));
+        }
+
+        let bucket = |rb: &RingBuffer, key: &GroupKey| -> RingBuffer {
+            let grouped = rb.grouped.as_ref().unwrap();
+            grouped.iter().find(|(k, _)| *k == key).unwrap().1.clone()
+        };
+
+        let uri = bucket(&rb, &GroupKey::URI(String::from("/a")));
+        assert_eq!(uri.stats.global.requests, 3);
+
+        let ip = bucket(&uri, &GroupKey::IpAddress("10.0.0.1".parse().unwrap()));
+        assert_eq!(ip.stats.global.requests, 2);
+
+        let status = bucket(&ip, &GroupKey::StatusCode(404));
+        assert_eq!(status.stats.global.requests, 1);
+
+        // the chain ends there
+        assert!(status.grouped.is_none());
+
+        // eviction reaches every level, dropping emptied buckets
+        clock.advance(chrono::Duration::seconds(35));
+        rb.cleanup(clock.now()).unwrap();
+        assert_eq!(rb.buffer.len(), 2);
+
+        let uri = bucket(&rb, &GroupKey::URI(String::from("/a")));
+        let ips: Vec<_> = uri
+            .grouped
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|(k, _)| k.to_string())
+            .collect();
+        assert_eq!(ips, vec!["10.0.0.2"]);
+    }
+
+    #[test]
+    fn memory_grows_with_depth() {
+        let mut flat = grouped_ring_buffer("60s", "uri");
+        let mut nested = grouped_ring_buffer("60s", "uri,ip,status");
+
+        for i in 0..50 {
+            let mut request = Request::blank();
+            request.uri = format!("/{}", i % 5);
+            request.status_code = 200 + i % 3;
+            let request = Arc::new(request);
+
+            flat.push(Arc::clone(&request)).unwrap();
+            nested.push(request).unwrap();
+        }
+
+        assert!(nested.memory() > flat.memory());
+        assert_eq!(nested.request_memory(), flat.request_memory());
+
+        // the running totals go back down as requests are evicted
+        while !nested.buffer.is_empty() {
+            nested.pop();
+        }
+        assert_eq!(nested.grouped.as_ref().unwrap().memory(), 0);
+        assert_eq!(nested.request_memory(), 0);
+    }
+
+    #[test]
+    fn filter_limits_grouped_rows_only() {
+        let clock = ManualClock::new(at("2020-05-01T10:00:00Z"));
+        let mut rb = ring_buffer("60s");
+
+        for uri in ["/api/a", "/api/b", "/index.html", "/api/a"].iter() {
+            rb.push(request(clock.now(), uri)).unwrap();
+        }
+
+        let rows = |rb: &RingBuffer| {
+            let mut rows: Vec<_> = rb
+                .grouped
+                .as_ref()
+                .unwrap()
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.buffer.len()))
+                .collect();
+            rows.sort();
+            rows
+        };
+
+        rb.refilter(Some("uri~^/api/".parse().unwrap()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            rows(&rb),
+            vec![(String::from("/api/a"), 2), (String::from("/api/b"), 1)]
+        );
+
+        // new requests are filtered too, but the window stats aren't
+        rb.push(request(clock.now(), "/other")).unwrap();
+        rb.push(request(clock.now(), "/api/b")).unwrap();
+        assert_eq!(rb.stats.global.requests, 6);
+        assert_eq!(rows(&rb).len(), 2);
+
+        // eviction copes with requests that were never grouped
+        clock.advance(chrono::Duration::seconds(60));
+        rb.cleanup(clock.now()).unwrap();
+        assert!(rb.buffer.is_empty());
+        assert!(rows(&rb).is_empty());
+
+        rb.push(request(clock.now(), "/other")).unwrap();
+        rb.refilter(None).unwrap().unwrap();
+        assert_eq!(rows(&rb), vec![(String::from("/other"), 1)]);
+    }
+
+    #[test]
+    fn rows_sort_by_any_column_either_way() {
+        use std::cmp::Ordering;
+
+        let clock = ManualClock::new(at("2020-05-01T10:00:00Z"));
+        let mut rb = ring_buffer("60s");
+
+        for (uri, status) in [("/a", 200), ("/a", 200), ("/b", 500)].iter() {
+            let mut r = Request::blank();
+            r.time = clock.now().into();
+            r.uri = String::from(*uri);
+            r.status_code = *status;
+            rb.push(Arc::new(r)).unwrap();
+        }
+
+        let order = |order: &str| {
+            rb.options.lock().unwrap().order = order.parse().unwrap();
+            let grouped = rb.grouped.as_ref().unwrap();
+            let a = grouped.get(&GroupKey::URI(String::from("/a"))).unwrap();
+            let b = grouped.get(&GroupKey::URI(String::from("/b"))).unwrap();
+            a.cmp(b)
+        };
+
+        assert_eq!(order("requests"), Ordering::Greater);
+        assert_eq!(order("reqs:asc"), Ordering::Less);
+        assert_eq!(order("err%"), Ordering::Less);
+        assert_eq!(order("5xx:desc"), Ordering::Less);
+
+        // no times at all, so nothing to tell them apart
+        assert_eq!(order("p95"), Ordering::Equal);
+
+        assert_eq!("p95:asc".parse::<Order>().unwrap().to_string(), "p95:asc");
+        assert!("p95:up".parse::<Order>().is_err());
+        assert!("colour".parse::<Order>().is_err());
+    }
+
+    #[test]
+    fn rows_without_times_sort_last_either_way() {
+        use std::cmp::Ordering;
+
+        let clock = ManualClock::new(at("2020-05-01T10:00:00Z"));
+        let mut rb = ring_buffer("60s");
+
+        for (uri, time) in [("/timed", Some(250)), ("/untimed", None)].iter() {
+            let mut r = Request::blank();
+            r.time = clock.now().into();
+            r.uri = String::from(*uri);
+            r.response_time = time.map(std::time::Duration::from_millis);
+            rb.push(Arc::new(r)).unwrap();
+        }
+
+        let order = |order: &str| {
+            rb.options.lock().unwrap().order = order.parse().unwrap();
+            let grouped = rb.grouped.as_ref().unwrap();
+            let timed = grouped.get(&GroupKey::URI(String::from("/timed"))).unwrap();
+            let untimed = grouped
+                .get(&GroupKey::URI(String::from("/untimed")))
+                .unwrap();
+            timed.cmp(untimed)
+        };
+
+        // greater is shown first
+        assert_eq!(order("p95"), Ordering::Greater);
+        assert_eq!(order("p95:asc"), Ordering::Greater);
+        assert_eq!(order("max:asc"), Ordering::Greater);
+    }
+}
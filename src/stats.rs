@@ -1,9 +1,21 @@
 use crate::prelude::*;
 
+use crate::histogram::Histogram;
+
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub struct Counters {
     pub requests: i64,
     pub bytes: i64,
+
+    // requests which had a response time logged, and the sum of those times
+    // in microseconds, for averaging
+    pub timed_requests: i64,
+    pub time_total: i64,
+
+    // distribution of response times (microseconds), for min, max and
+    // percentiles; this supports removal so it stays correct as requests
+    // leave a RingBuffer
+    pub latency: Histogram,
 }
 
 impl Counters {
@@ -11,17 +23,60 @@ impl Counters {
         Self {
             requests: 0,
             bytes: 0,
+            timed_requests: 0,
+            time_total: 0,
+            latency: Histogram::new(),
         }
     }
 
     pub fn add_request(&mut self, request: &Request) {
         self.requests += 1;
         self.bytes += request.size;
+
+        if let Some(t) = request.response_time {
+            let t = t.as_micros() as i64;
+            self.timed_requests += 1;
+            self.time_total += t;
+            self.latency.record(t as u64);
+        }
     }
 
     pub fn remove_request(&mut self, request: &Request) {
         self.requests -= 1;
         self.bytes -= request.size;
+
+        if let Some(t) = request.response_time {
+            let t = t.as_micros() as i64;
+            self.timed_requests -= 1;
+            self.time_total -= t;
+            self.latency.remove(t as u64);
+        }
+    }
+
+    // these all return None if no requests had a response time logged
+
+    pub fn avg_time(&self) -> Option<std::time::Duration> {
+        if self.timed_requests > 0 {
+            let avg = self.time_total / self.timed_requests;
+            Some(std::time::Duration::from_micros(avg as u64))
+        } else {
+            None
+        }
+    }
+
+    pub fn min_time(&self) -> Option<std::time::Duration> {
+        self.latency.min().map(std::time::Duration::from_micros)
+    }
+
+    pub fn max_time(&self) -> Option<std::time::Duration> {
+        self.latency.max().map(std::time::Duration::from_micros)
+    }
+
+    // eg percentile_time(95.0) for p95
+    pub fn percentile_time(&self, pct: f64) -> Option<std::time::Duration> {
+        self.latency
+            .percentile(pct)
+            .map(std::time::Duration::from_micros)
     }
 }
 
@@ -49,21 +104,31 @@ impl Stats {
     }
 
     pub fn add_request(&mut self, request: &Request) {
-        self.global.add_request(&request);
+        self.global.add_request(request);
 
         // remove from appropriate HTTP status code Stats too
-        let i = Self::status_code_stats_index_for_request(&request);
+        let i = Self::status_code_stats_index_for_request(request);
         let status_code_stats = &mut self.by_status_code[i];
-        status_code_stats.add_request(&request);
+        status_code_stats.add_request(request);
     }
 
     pub fn remove_request(&mut self, request: &Request) {
-        self.global.remove_request(&request);
+        self.global.remove_request(request);
 
         // remove from appropriate HTTP status code Stats too
-        let i = Self::status_code_stats_index_for_request(&request);
+        let i = Self::status_code_stats_index_for_request(request);
         let status_code_stats = &mut self.by_status_code[i];
-        status_code_stats.remove_request(&request);
+        status_code_stats.remove_request(request);
+    }
+
+    // Rough heap usage, for memory accounting; the rest is inline.
+    pub fn memory(&self) -> usize {
+        self.global.latency.memory()
+            + self
+                .by_status_code
+                .iter()
+                .map(|c| c.latency.memory())
+                .sum::<usize>()
     }
 
     fn status_code_stats_index_for_request(request: &Request) -> usize {
@@ -77,3 +142,52 @@ impl Stats {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn request(status_code: i64, response_time: Option<Duration>) -> Request {
+        let mut request = Request::blank();
+        request.status_code = status_code;
+        request.size = 100;
+        request.response_time = response_time;
+        request
+    }
+
+    #[test]
+    fn times_average_over_timed_requests_only() {
+        let requests = [
+            request(200, Some(Duration::from_millis(10))),
+            request(200, None),
+            request(503, Some(Duration::from_millis(30))),
+        ];
+
+        let mut stats = Stats::new();
+        assert_eq!(stats.global.avg_time(), None);
+        for r in requests.iter() {
+            stats.add_request(r);
+        }
+
+        assert_eq!(stats.global.requests, 3);
+        assert_eq!(stats.global.bytes, 300);
+        assert_eq!(stats.global.timed_requests, 2);
+        assert_eq!(stats.global.avg_time(), Some(Duration::from_millis(20)));
+        assert_eq!(stats.global.max_time(), Some(Duration::from_millis(30)));
+        assert_eq!(stats.by_status_code[5].requests, 1);
+
+        // the longest time a log can give, see Field::seconds
+        let mut slow = Stats::new();
+        for _ in 0..1000 {
+            slow.add_request(&request(200, Some(Duration::from_secs(86400))));
+        }
+        assert_eq!(slow.global.avg_time(), Some(Duration::from_secs(86400)));
+
+        for r in requests.iter() {
+            stats.remove_request(r);
+        }
+        assert_eq!(stats, Stats::new());
+    }
+}
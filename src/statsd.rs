@@ -0,0 +1,178 @@
+use crate::prelude::*;
+
+use std::net::UdpSocket;
+
+// Pushes counters from alltime_stats to a StatsD daemon every interval, for
+// hosts which aren't scraped (see Metrics for that). Counters are sent as the
+// increase since the last push, which is what StatsD expects.
+//
+// Tags are added in the DogStatsD format, eg requests:5|c|#env:prod,role:web
+pub struct Statsd {
+    socket: UdpSocket,
+
+    prefix: String,
+    tags: Vec<String>,
+
+    alltime_stats: Arc<Mutex<Stats>>,
+
+    // totals at the last push
+    sent: Stats,
+}
+
+// names for Stats::by_status_code, skipping unknown codes at 0
+const STATUS_CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+impl Statsd {
+    pub fn new(
+        addr: &str,
+        prefix: &str,
+        tags: Vec<String>,
+        alltime_stats: Arc<Mutex<Stats>>,
+    ) -> Result<Self, Error> {
+        use std::net::ToSocketAddrs;
+
+        let addr = addr
+            .to_socket_addrs()
+            .with_context(|| format!("failed to resolve statsd address {}", addr))?
+            .next()
+            .ok_or_else(|| anyhow!("no addresses found for statsd address {}", addr))?;
+
+        let socket = UdpSocket::bind(if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        })?;
+        socket.connect(addr)?;
+
+        Ok(Self {
+            socket,
+            prefix: prefix.trim_end_matches('.').to_string(),
+            tags,
+            alltime_stats,
+            sent: Stats::new(),
+        })
+    }
+
+    pub fn start(
+        mut self,
+        interval: std::time::Duration,
+    ) -> Result<thread::JoinHandle<Result<(), Error>>, Error> {
+        let c = move || -> Result<_, _> {
+            loop {
+                thread::sleep(interval);
+
+                // the daemon may just not be running yet; keep trying
+                if let Err(e) = self.send() {
+                    error!("statsd: {:?}", e);
+                }
+            }
+        };
+
+        Ok(thread::Builder::new().name("statsd".to_string()).spawn(c)?)
+    }
+
+    // Send one packet with a line per counter.
+    fn send(&mut self) -> Result<(), Error> {
+        let stats = self.alltime_stats.lock().unwrap().clone();
+
+        let mut lines = vec![
+            self.counter(
+                "requests",
+                stats.global.requests - self.sent.global.requests,
+            ),
+            self.counter("bytes", stats.global.bytes - self.sent.global.bytes),
+        ];
+
+        for (i, class) in STATUS_CLASSES.iter().enumerate() {
+            let requests = stats.by_status_code[i + 1].requests;
+            let sent = self.sent.by_status_code[i + 1].requests;
+            lines.push(self.counter(&format!("status.{}", class), requests - sent));
+        }
+
+        self.socket.send(lines.join("\n").as_bytes())?;
+        self.sent = stats;
+
+        Ok(())
+    }
+
+    // eg apachetop.requests:12|c|#env:prod
+    fn counter(&self, name: &str, value: i64) -> String {
+        let mut line = if self.prefix.is_empty() {
+            format!("{}:{}|c", name, value)
+        } else {
+            format!("{}.{}:{}|c", self.prefix, name, value)
+        };
+
+        if !self.tags.is_empty() {
+            line.push_str("|#");
+            line.push_str(&self.tags.join(","));
+        }
+
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receive(listener: &UdpSocket) -> String {
+        let mut buf = [0; 1500];
+        let len = listener.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    fn add_request(stats: &Mutex<Stats>, status_code: i64) {
+        let mut request = Request::blank();
+        request.status_code = status_code;
+        request.size = 100;
+        stats.lock().unwrap().add_request(&request);
+    }
+
+    #[test]
+    fn sends_counter_increases_with_prefix_and_tags() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let stats = Arc::new(Mutex::new(Stats::new()));
+        let tags = vec![String::from("env:test"), String::from("canary")];
+        let mut statsd = Statsd::new(&addr, "web.apachetop.", tags, Arc::clone(&stats)).unwrap();
+
+        add_request(&stats, 200);
+        add_request(&stats, 200);
+        add_request(&stats, 503);
+        statsd.send().unwrap();
+
+        assert_eq!(
+            receive(&listener),
+            "web.apachetop.requests:3|c|#env:test,canary\n\
+             web.apachetop.bytes:300|c|#env:test,canary\n\
+             web.apachetop.status.1xx:0|c|#env:test,canary\n\
+             web.apachetop.status.2xx:2|c|#env:test,canary\n\
+             web.apachetop.status.3xx:0|c|#env:test,canary\n\
+             web.apachetop.status.4xx:0|c|#env:test,canary\n\
+             web.apachetop.status.5xx:1|c|#env:test,canary"
+        );
+
+        // only what's new since the last send
+        add_request(&stats, 404);
+        statsd.send().unwrap();
+
+        let packet = receive(&listener);
+        assert!(packet.starts_with("web.apachetop.requests:1|c|#env:test,canary\n"));
+        assert!(packet.contains("web.apachetop.status.2xx:0|c"));
+        assert!(packet.contains("web.apachetop.status.4xx:1|c"));
+    }
+
+    #[test]
+    fn no_prefix_or_tags() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let statsd = Statsd::new(&addr, "", vec![], Arc::new(Mutex::new(Stats::new()))).unwrap();
+        assert_eq!(statsd.counter("requests", 7), "requests:7|c");
+    }
+}
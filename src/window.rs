@@ -1,6 +1,8 @@
 use crate::prelude::*;
 
-use crossterm::style::{Attribute, Print, Styler};
+use crate::keys::{self, Action};
+
+use crossterm::style::{style, Attribute, Print, Styler};
 use crossterm::{cursor, terminal, ExecutableCommand, QueueableCommand};
 
 use std::io::{stdout, Write};
@@ -14,28 +16,103 @@ pub struct Window {
     cols: u16,
 
     options: Arc<Mutex<Options>>,
+    clock: Arc<dyn Clock>,
 
     alltime_stats: Arc<Mutex<Stats>>,
     ring_buffer: Arc<Mutex<RingBuffer>>,
+
+    // keys of the rows opened with Enter, from the main table down through
+    // the --group chain; empty when showing the main table
+    path: Vec<GroupKey>,
+
+    // selection in the main table, then in each level of path
+    cursors: Vec<Cursor>,
+
+    // size of the table the cursor is in, as last drawn, for paging
+    page: usize,
+
+    // a line being typed at the bottom of the screen, if any
+    prompt: Option<Prompt>,
+
+    // rows with keys matching this are underlined, and n and N jump to them
+    search: Option<regex::Regex>,
+
+    // showing the keys and current settings over everything else
+    help: bool,
+
+    // shown at the bottom until the next key
+    message: Option<String>,
+
+    // the --include and --exclude rules, for the help overlay; they can't
+    // change while running, so are described once
+    prefilter: String,
+}
+
+struct Prompt {
+    kind: PromptKind,
+    input: String,
+
+    // why the input wasn't accepted, until it's edited
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum PromptKind {
+    // a new --filter expression
+    Filter,
+
+    // a regex or plain text to find in the table's keys, as typed
+    Search,
+
+    // a new --columns list
+    Columns,
+}
+
+#[derive(Default, Debug, Clone)]
+struct Cursor {
+    // the selected group, so it stays selected as rows are re-ranked; None
+    // until a row is drawn as selected, or after moving by rows
+    key: Option<GroupKey>,
+
+    // the selected group's row as last drawn, or the row moved to
+    selected: usize,
+
+    // first row shown
+    scroll: usize,
 }
 
+// number of recent requests shown in a detail view, if there's room
+const RECENT_REQUESTS: usize = 10;
+
 impl Window {
     pub fn new(
         options: Arc<Mutex<Options>>,
+        clock: Arc<dyn Clock>,
         alltime_stats: Arc<Mutex<Stats>>,
         ring_buffer: Arc<Mutex<RingBuffer>>,
-    ) -> Self {
-        let (cols, lines) = crossterm::terminal::size().unwrap();
+    ) -> Result<Self, Error> {
+        let (cols, lines) =
+            terminal::size().context("failed to get terminal size (try --batch)")?;
 
-        let now = std::time::Instant::now();
-        Window {
-            started_at: StartedAt(now),
+        let prefilter = Prefilter::from_options(&options.lock().unwrap()).to_string();
+
+        Ok(Window {
+            started_at: StartedAt(Arc::clone(&clock)),
             lines,
             cols,
             options,
+            clock,
             alltime_stats,
             ring_buffer,
-        }
+            path: vec![],
+            cursors: vec![Cursor::default()],
+            page: 1,
+            prompt: None,
+            search: None,
+            help: false,
+            message: None,
+            prefilter,
+        })
     }
 
     pub fn run(&mut self) -> Result<(), Error> {
@@ -46,7 +123,8 @@ impl Window {
         // support f64 seconds by multiplying then using from_millis
         let interval = std::time::Duration::from_millis((interval * 1000.0) as u64);
 
-        crossterm::terminal::enable_raw_mode()?;
+        crossterm::terminal::enable_raw_mode()
+            .context("failed to set up terminal (try --batch)")?;
 
         // stdout().execute(crossterm::event::EnableMouseCapture)?
         stdout().execute(cursor::Hide)?;
@@ -72,15 +150,21 @@ impl Window {
 
     fn redraw(&mut self) -> Result<(), Error> {
         let mut stdout = stdout();
+        let now = self.clock.now();
+        let title = format!("apachetop {}", CARGO_PKG_VERSION);
 
         stdout
             .queue(terminal::Clear(terminal::ClearType::All))?
             .queue(cursor::MoveTo(0, 0))?
-            .queue(Print(format!("apachetop {}", CARGO_PKG_VERSION)))?
+            .queue(Print(&title))?
             .queue(cursor::MoveTo(self.cols / 2, 0))?
             .queue(Print(self.started_at.to_string()))?
-            .queue(cursor::MoveTo(self.cols - 8 as u16, 0))?
-            .queue(Print(chrono::Local::now().format("%H:%M:%S").to_string()))?;
+            .queue(cursor::MoveTo(self.cols - 8, 0))?
+            .queue(Print(
+                now.with_timezone(&chrono::Local)
+                    .format("%H:%M:%S")
+                    .to_string(),
+            ))?;
 
         {
             let alltime_stats = self.alltime_stats.lock().unwrap();
@@ -98,18 +182,41 @@ impl Window {
         } // mutex on alltime_stats
 
         {
-            let mut ring_buffer = self.ring_buffer.lock().unwrap();
+            // cloned so the guard doesn't borrow self, which draw_* update
+            let ring_buffer = Arc::clone(&self.ring_buffer);
+            let mut ring_buffer = ring_buffer.lock().unwrap();
 
             // TODO: better in another thread, not at display time?
-            ring_buffer.cleanup()?;
+            ring_buffer.cleanup(now)?;
 
-            let elapsed = match ring_buffer.first() {
-                Some(f) => {
-                    let first = chrono::DateTime::<chrono::Utc>::from(f.time);
-                    (chrono::Utc::now() - first).num_seconds() as f64
-                }
-                None => 1.0, // avoid divide by zero later
-            };
+            let elapsed = ring_buffer.elapsed(now);
+
+            let memory = ring_buffer.memory() + ring_buffer.request_memory();
+            stdout
+                .queue(cursor::MoveTo(self.cols.saturating_sub(22), 0))?
+                .queue(Print(format!(
+                    "mem: {}",
+                    Self::humansize(memory as f64).trim()
+                )))?;
+
+            // out of order lines, see RingBuffer::push, and lines thrown
+            // away by --include and --exclude options
+            let mut counts = vec![];
+            if ring_buffer.late > 0 {
+                counts.push(format!("late: {}", ring_buffer.late));
+            }
+            if ring_buffer.dropped > 0 {
+                counts.push(format!("dropped: {}", ring_buffer.dropped));
+            }
+            // between the title and the runtime, cut short if there's no room
+            let left = title.len() + 2;
+            let room = (self.cols as usize / 2).saturating_sub(left + 1);
+            if !counts.is_empty() && room > 0 {
+                let counts: String = counts.join(" ").chars().take(room).collect();
+                stdout
+                    .queue(cursor::MoveTo(left as u16, 0))?
+                    .queue(Print(counts))?;
+            }
 
             stdout
                 .queue(cursor::MoveTo(0, 3))?
@@ -119,73 +226,386 @@ impl Window {
                     false,
                 )))?
                 .queue(cursor::MoveTo(0, 4))?
-                .queue(Print(self.per_code_line(&ring_buffer.stats)))?;
+                .queue(Print(self.per_code_line(&ring_buffer.stats)))?
+                .queue(cursor::MoveTo(0, 5))?
+                .queue(Print(Self::time_line(&ring_buffer.stats)))?;
 
-            {
-                let options = self.options.lock().unwrap();
-                stdout.queue(cursor::MoveTo(0, 6))?.queue(Print(
-                    format!(
-                        "{:width$}",
-                        &format!(
-                            "  REQS REQS/S    SIZE    SZ/S {}",
-                            options.group.to_string()
-                        ),
-                        width = self.cols as usize
-                    )
-                    .negative(),
-                ))?;
-            } // read lock on options
-
-            if let Some(grouped) = &ring_buffer.grouped {
-                use lazysort::SortedBy;
-
-                // convert HashMap<GroupKey, RingBuffer> to Vec<(GroupKey, RingBuffer)>,
-                // sort it by the RingBuffers, then lazy-sort the first n lines for display.
-                for (key, ring_buffer) in grouped
-                    .iter()
-                    .filter(|(_, v)| !v.buffer.is_empty()) // filter out empty buffers to save work
-                    .collect::<Vec<(&GroupKey, &RingBuffer)>>()
-                    .iter()
-                    .sorted_by(|a, b| b.1.cmp(&a.1)) // see impl Ord for RingBuffer
-                    .take((self.lines - 7/* lines used for header */) as usize)
-                {
-                    stdout
-                        .queue(cursor::MoveToNextLine(1))?
-                        .queue(Print(self.table_line(key, ring_buffer, elapsed)))?;
-                }
+            if self.path.is_empty() {
+                let height = self.lines.saturating_sub(6 /* lines used for header */);
+                self.draw_table(&mut stdout, &ring_buffer, 6, height, elapsed)?;
+            } else {
+                self.draw_detail(&mut stdout, &ring_buffer, elapsed)?;
             }
         } // mutex on ring_buffer
 
+        let bottom = match (&self.prompt, &self.message) {
+            (Some(prompt), _) => {
+                let mut line = format!("{}: {}_", prompt.kind, prompt.input);
+                if let Some(ref error) = prompt.error {
+                    line = format!("{}    ({})", line, error);
+                }
+                Some(line)
+            }
+            (None, Some(message)) => Some(message.clone()),
+            (None, None) => None,
+        };
+        if let Some(line) = bottom {
+            stdout
+                .queue(cursor::MoveTo(0, self.lines.saturating_sub(1)))?
+                .queue(terminal::Clear(terminal::ClearType::CurrentLine))?
+                .queue(Print(self.truncate(&line)))?;
+        }
+
+        if self.help {
+            self.draw_help(&mut stdout)?;
+        }
+
         stdout.flush()?;
 
         Ok(())
     }
 
+    // Header at row top, then up to height - 1 rows of rb's groups, with the
+    // current cursor's row highlighted.
+    fn draw_table(
+        &mut self,
+        stdout: &mut std::io::Stdout,
+        rb: &RingBuffer,
+        top: u16,
+        height: u16,
+        elapsed: f64,
+    ) -> Result<(), Error> {
+        use lazysort::SortedBy;
+
+        let grouped = match &rb.grouped {
+            Some(grouped) => grouped,
+            None => return Ok(()),
+        };
+
+        // filter out empty buffers to save work
+        let rows: Vec<(&GroupKey, &RingBuffer)> = grouped
+            .iter()
+            .filter(|(_, v)| !v.buffer.is_empty())
+            .collect();
+
+        let o = self.options.lock().unwrap();
+        let columns = Column::shown(&o.columns, &rb.stats);
+        let direction = if o.order.ascending { "asc" } else { "desc" };
+
+        let mut header = format!(
+            "{} {}    sort: {} {}",
+            Self::table_header(&columns),
+            grouped.grouping(),
+            o.order.column,
+            direction
+        );
+        if let Some(ref filter) = o.filter {
+            header = format!("{}    filter: {}", header, filter);
+        }
+        drop(o);
+        if let Some(ref search) = self.search {
+            let matches = rows
+                .iter()
+                .filter(|(key, _)| search.is_match(&key.to_string()))
+                .count();
+            header = format!("{}    search: {} ({} found)", header, search, matches);
+        }
+        stdout.queue(cursor::MoveTo(0, top))?.queue(Print(
+            format!(
+                "{:width$}",
+                self.truncate(&header),
+                width = self.cols as usize
+            )
+            .negative(),
+        ))?;
+
+        // keep the selection on the same group wherever it now ranks, or if
+        // it's gone, on the same row; and in view
+        let page = std::cmp::max(height.saturating_sub(1) as usize, 1);
+        let cursor = self.cursors.last_mut().unwrap();
+        if let Some(i) = cursor
+            .key
+            .as_ref()
+            .and_then(|key| Self::row_index(&rows, key))
+        {
+            cursor.selected = i;
+        }
+        cursor.selected = std::cmp::min(cursor.selected, rows.len().saturating_sub(1));
+        if cursor.selected < cursor.scroll {
+            cursor.scroll = cursor.selected;
+        } else if cursor.selected >= cursor.scroll + page {
+            cursor.scroll = cursor.selected + 1 - page;
+        }
+        let cursor = cursor.clone();
+        self.page = page;
+        let mut selected_key = None;
+
+        // sort by the RingBuffers, then lazy-sort only as far as the lines shown
+        let now = self.clock.now();
+        for (i, (key, ring_buffer)) in rows
+            .iter()
+            .sorted_by(Self::row_order)
+            .enumerate()
+            .skip(cursor.scroll)
+            .take(page)
+        {
+            let line = self.table_line(&columns, key, ring_buffer, elapsed, now);
+            let row = top + 1 + (i - cursor.scroll) as u16;
+
+            let mut line = style(line);
+            if i == cursor.selected {
+                line = line.negative();
+                selected_key = Some((*key).clone());
+            }
+            if self.search_matches(key) {
+                line = line.underlined();
+            }
+
+            stdout.queue(cursor::MoveTo(0, row))?.queue(Print(line))?;
+        }
+
+        self.cursors.last_mut().unwrap().key = selected_key;
+
+        Ok(())
+    }
+
+    // A box in the middle of the screen listing every key, then the
+    // settings they change.
+    fn draw_help(&self, stdout: &mut std::io::Stdout) -> Result<(), Error> {
+        let mut lines: Vec<String> = keys::BINDINGS
+            .iter()
+            .map(|binding| format!("{:>20}  {}", binding.key_names(), binding.description))
+            .collect();
+        lines.push(String::new());
+
+        let dropped = self.ring_buffer.lock().unwrap().dropped;
+        let o = self.options.lock().unwrap();
+        let group: Vec<String> = o.group_chain.iter().map(|g| g.to_string()).collect();
+        let none = || String::from("none");
+
+        let columns: Vec<String> = o.columns.iter().map(|c| c.to_string()).collect();
+        lines.push(format!("{:>20}  {}", "columns", columns.join(",")));
+        lines.push(format!("{:>20}  {}", "order", o.order));
+        lines.push(format!("{:>20}  {}", "group", group.join(" > ")));
+        lines.push(format!(
+            "{:>20}  {}",
+            "filter",
+            o.filter.as_ref().map_or_else(none, |f| f.to_string())
+        ));
+        lines.push(format!(
+            "{:>20}  {}",
+            "search",
+            self.search.as_ref().map_or_else(none, |s| s.to_string())
+        ));
+        if !self.prefilter.is_empty() {
+            lines.push(format!(
+                "{:>20}  {} ({} dropped)",
+                "dropping", self.prefilter, dropped
+            ));
+        }
+        drop(o);
+
+        // no room for even a border
+        if self.lines < 3 {
+            return Ok(());
+        }
+
+        let width = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0) + 2;
+        let width = std::cmp::min(width, self.cols as usize);
+        let height = std::cmp::min(lines.len() + 2, self.lines as usize);
+        let left = (self.cols as usize - width) / 2;
+        let top = (self.lines as usize - height) / 2;
+
+        let title = format!(
+            "{:^width$}",
+            "apachetop keys (any key to close)",
+            width = width
+        );
+        stdout
+            .queue(cursor::MoveTo(left as u16, top as u16))?
+            .queue(Print(self.truncate(&title).negative()))?;
+
+        for (i, line) in lines.iter().take(height - 2).enumerate() {
+            let line: String = format!(" {:width$}", line, width = width)
+                .chars()
+                .take(width)
+                .collect();
+            stdout
+                .queue(cursor::MoveTo(left as u16, (top + 1 + i) as u16))?
+                .queue(Print(line))?;
+        }
+
+        let blank = format!("{:width$}", "", width = width);
+        stdout
+            .queue(cursor::MoveTo(left as u16, (top + height - 1) as u16))?
+            .queue(Print(blank.negative()))?;
+
+        Ok(())
+    }
+
+    // See impl Ord for RingBuffer. Ties are broken by key, so the selected
+    // row doesn't move around between redraws.
+    fn row_order(
+        a: &&(&GroupKey, &RingBuffer),
+        b: &&(&GroupKey, &RingBuffer),
+    ) -> std::cmp::Ordering {
+        b.1.cmp(a.1).then_with(|| a.0.cmp(b.0))
+    }
+
+    // Where key's row is in row_order, without sorting all the rows.
+    fn row_index(rows: &[(&GroupKey, &RingBuffer)], key: &GroupKey) -> Option<usize> {
+        let row = rows.iter().find(|(k, _)| *k == key)?;
+        Some(
+            rows.iter()
+                .filter(|other| Self::row_order(other, &row) == std::cmp::Ordering::Less)
+                .count(),
+        )
+    }
+
+    // The group opened with Enter: its own stats, then its rows of the next
+    // grouping in the chain (if any), then its most recent requests.
+    fn draw_detail(
+        &mut self,
+        stdout: &mut std::io::Stdout,
+        ring_buffer: &RingBuffer,
+        elapsed: f64,
+    ) -> Result<(), Error> {
+        let title = self.breadcrumb(ring_buffer);
+        stdout.queue(cursor::MoveTo(0, 6))?.queue(Print(
+            format!("{:width$}", title, width = self.cols as usize).negative(),
+        ))?;
+
+        let rb = match Self::bucket(ring_buffer, &self.path) {
+            Some(rb) => rb,
+            None => {
+                stdout
+                    .queue(cursor::MoveTo(0, 7))?
+                    .queue(Print("No requests left in the window. Esc to go back."))?;
+                return Ok(());
+            }
+        };
+
+        stdout
+            .queue(cursor::MoveTo(0, 7))?
+            .queue(Print(self.primary_stats_line(&rb.stats, elapsed, false)))?
+            .queue(cursor::MoveTo(0, 8))?
+            .queue(Print(self.per_code_line(&rb.stats)))?
+            .queue(cursor::MoveTo(0, 9))?
+            .queue(Print(self.truncate(&Self::status_codes_line(rb))))?
+            .queue(cursor::MoveTo(0, 10))?
+            .queue(Print(Self::time_line(&rb.stats)))?;
+
+        // split what's left between the next grouping and recent requests
+        let mut top = 11;
+        let left = self.lines.saturating_sub(top);
+        let recent = std::cmp::min(RECENT_REQUESTS as u16 + 1, left / 2);
+
+        if rb.grouped.is_some() {
+            let height = left - recent;
+            self.draw_table(stdout, rb, top, height, elapsed)?;
+            top += height;
+        } else {
+            top = self.lines.saturating_sub(recent);
+        }
+
+        if recent > 1 {
+            stdout.queue(cursor::MoveTo(0, top))?.queue(Print(
+                format!("{:width$}", "RECENT REQUESTS", width = self.cols as usize).negative(),
+            ))?;
+
+            for (i, request) in rb
+                .buffer
+                .iter()
+                .rev()
+                .take((recent - 1) as usize)
+                .enumerate()
+            {
+                stdout
+                    .queue(cursor::MoveTo(0, top + 1 + i as u16))?
+                    .queue(Print(self.truncate(&Self::request_line(request))))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // eg URI /index.html > IP 10.0.0.1    (Esc to go back)
+    fn breadcrumb(&self, ring_buffer: &RingBuffer) -> String {
+        let mut parts = vec![];
+        let mut rb = Some(ring_buffer);
+
+        for key in self.path.iter() {
+            let grouped = rb.and_then(|rb| rb.grouped.as_ref());
+            match grouped {
+                Some(grouped) => parts.push(format!("{} {}", grouped.grouping(), key)),
+                None => parts.push(key.to_string()),
+            }
+            rb = grouped.and_then(|grouped| grouped.get(key));
+        }
+
+        format!("{}    (Esc to go back)", parts.join(" > "))
+    }
+
+    // the RingBuffer for a path of keys from the main table down
+    fn bucket<'a>(rb: &'a RingBuffer, path: &[GroupKey]) -> Option<&'a RingBuffer> {
+        path.iter()
+            .try_fold(rb, |rb, key| rb.grouped.as_ref()?.get(key))
+    }
+
+    // Codes: 200 x 1234, 304 x 56, 404 x 7
+    fn status_codes_line(rb: &RingBuffer) -> String {
+        let mut counts: std::collections::BTreeMap<i64, u64> = std::collections::BTreeMap::new();
+        for request in rb.buffer.iter() {
+            *counts.entry(request.status_code).or_insert(0) += 1;
+        }
+
+        let codes: Vec<String> = counts
+            .iter()
+            .map(|(code, count)| format!("{} x {}", code, count))
+            .collect();
+
+        format!("Codes: {}", codes.join(", "))
+    }
+
+    // 10:01:02 10.0.0.1        GET     200   1.20K  12ms /index.html
+    fn request_line(request: &Request) -> String {
+        format!(
+            "{} {:15} {:7} {} {:>7} {:>6} {}",
+            request.time.format("%H:%M:%S"),
+            request.ip_address.to_string(),
+            format!("{:?}", request.method),
+            request.status_code,
+            Self::humansize(request.size as f64),
+            Self::humantime(request.response_time),
+            request.uri
+        )
+    }
+
+    fn truncate(&self, line: &str) -> String {
+        line.chars().take(self.cols as usize).collect()
+    }
+
     fn handle_event(&mut self) -> Result<bool, Error> {
         use crossterm::event::Event::{Key, Mouse, Resize};
-        use crossterm::event::KeyCode::Char;
-        use crossterm::event::{KeyEvent, KeyModifiers};
 
         match crossterm::event::read()? {
-            Key(KeyEvent {
-                code: Char('q'), ..
-            })
-            | Key(KeyEvent {
-                modifiers: KeyModifiers::CONTROL,
-                code: Char('c'),
-            }) => return Ok(false),
-            Key(KeyEvent {
-                code: Char('o'), ..
-            }) => {
-                self.toggle_sort();
-            }
-            Key(KeyEvent {
-                code: Char('g'), ..
-            }) => {
-                self.toggle_group();
-            }
+            // everything goes to the prompt while it's open
+            Key(event) if self.prompt.is_some() => self.prompt_key(event),
 
-            Key(event) => info!("{:?}", event),
+            // any key closes help
+            Key(_) if self.help => self.help = false,
+
+            Key(event) => {
+                self.message = None;
+
+                match keys::action(event) {
+                    Some(action) => return Ok(self.act(action)),
+                    None => {
+                        info!("{:?}", event);
+                        self.message = Some(String::from("unknown key; press ? for help"));
+                    }
+                }
+            }
             Mouse(event) => info!("{:?}", event),
             Resize(cols, lines) => {
                 self.lines = lines;
@@ -196,30 +616,358 @@ impl Window {
         Ok(true)
     }
 
+    // Do what a key is bound to (see keys::BINDINGS). False to quit.
+    fn act(&mut self, action: Action) -> bool {
+        match action {
+            Action::Quit => return false,
+            Action::Help => self.help = true,
+            Action::ToggleSort => self.toggle_sort(),
+            Action::ReverseSort => self.options.lock().unwrap().reverse_sort(),
+            Action::Columns => self.open_prompt(PromptKind::Columns),
+            Action::ToggleGroup => self.toggle_group(),
+            Action::Filter => self.open_prompt(PromptKind::Filter),
+            Action::Search => self.open_prompt(PromptKind::Search),
+            Action::NextMatch => self.find(Direction::Next),
+            Action::PreviousMatch => self.find(Direction::Previous),
+            Action::Up => self.move_cursor(-1),
+            Action::Down => self.move_cursor(1),
+            Action::PageUp => self.move_cursor(-(self.page as isize)),
+            Action::PageDown => self.move_cursor(self.page as isize),
+            Action::Top => self.move_cursor(isize::MIN),
+            Action::Bottom => self.move_cursor(isize::MAX),
+            Action::Open => self.open_selected(),
+            Action::Back => self.close(),
+        }
+
+        true
+    }
+
+    // only through the columns on screen, as chosen for the table in view
     fn toggle_sort(&self) {
-        self.options.lock().unwrap().toggle_sort();
+        let ring_buffer = self.ring_buffer.lock().unwrap();
+        let rb = Self::bucket(&ring_buffer, &self.path).unwrap_or(&ring_buffer);
+
+        let mut o = self.options.lock().unwrap();
+        let shown = Column::shown(&o.columns, &rb.stats);
+        o.toggle_sort(&shown);
     }
 
-    fn toggle_group(&self) {
+    // the rows are different now, so go back to the top of the main table
+    fn toggle_group(&mut self) {
         let mut o = self.options.lock().unwrap();
         let group_by = o.toggle_group();
         drop(o);
         self.ring_buffer.lock().unwrap().regroup(group_by);
+
+        self.path.clear();
+        self.cursors = vec![Cursor::default()];
+    }
+
+    // Start typing, with the current value to edit.
+    fn open_prompt(&mut self, kind: PromptKind) {
+        let input = match kind {
+            PromptKind::Filter => self
+                .options
+                .lock()
+                .unwrap()
+                .filter
+                .as_ref()
+                .map(|f| f.to_string())
+                .unwrap_or_default(),
+            PromptKind::Search => String::new(),
+            PromptKind::Columns => {
+                let o = self.options.lock().unwrap();
+                let columns: Vec<String> = o.columns.iter().map(|c| c.to_string()).collect();
+                columns.join(",")
+            }
+        };
+
+        self.prompt = Some(Prompt {
+            kind,
+            input,
+            error: None,
+        });
+    }
+
+    fn prompt_key(&mut self, event: crossterm::event::KeyEvent) {
+        use crossterm::event::KeyCode::{Backspace, Char, Enter, Esc};
+        use crossterm::event::{KeyEvent, KeyModifiers};
+
+        let prompt = match self.prompt.as_mut() {
+            Some(prompt) => prompt,
+            None => return,
+        };
+
+        match event {
+            KeyEvent { code: Esc, .. }
+            | KeyEvent {
+                modifiers: KeyModifiers::CONTROL,
+                code: Char('c'),
+            } => {
+                if prompt.kind == PromptKind::Search {
+                    self.search = None;
+                }
+                self.prompt = None;
+            }
+            KeyEvent { code: Enter, .. } => self.submit_prompt(),
+            KeyEvent {
+                code: Backspace, ..
+            } => {
+                prompt.input.pop();
+                prompt.error = None;
+                self.prompt_changed();
+            }
+            KeyEvent {
+                code: Char(c),
+                modifiers,
+            } if !modifiers.contains(KeyModifiers::CONTROL) => {
+                prompt.input.push(c);
+                prompt.error = None;
+                self.prompt_changed();
+            }
+            _ => {}
+        }
     }
 
-    fn table_line(&self, key: &GroupKey, rr: &RingBuffer, elapsed: f64) -> String {
-        let reqs = rr.stats.global.requests as f64;
+    // Search as you type, starting from the selected row.
+    fn prompt_changed(&mut self) {
+        let input = match self.prompt {
+            Some(Prompt {
+                kind: PromptKind::Search,
+                ref input,
+                ..
+            }) => input.clone(),
+            _ => return,
+        };
+
+        self.search = Self::search_regex(&input);
+        if self.search.is_some() {
+            self.find(Direction::Here);
+        }
+    }
+
+    // Use what was typed, or keep the prompt open with an error if it's no good.
+    fn submit_prompt(&mut self) {
+        let mut prompt = match self.prompt.take() {
+            Some(prompt) => prompt,
+            None => return,
+        };
+
+        match prompt.kind {
+            PromptKind::Filter => {
+                // an empty filter clears it
+                let input = prompt.input.trim();
+                if input.is_empty() {
+                    self.set_filter(None);
+                    return;
+                }
+
+                match input.parse() {
+                    Ok(filter) => self.set_filter(Some(filter)),
+                    Err(e) => {
+                        prompt.error = Some(format!("{:#}", e));
+                        self.prompt = Some(prompt);
+                    }
+                }
+            }
+
+            // already done while typing; just stop typing
+            PromptKind::Search => {}
+
+            PromptKind::Columns => match Self::parse_columns(&prompt.input) {
+                Ok(columns) => self.options.lock().unwrap().columns = columns,
+                Err(e) => {
+                    prompt.error = Some(format!("{:#}", e));
+                    self.prompt = Some(prompt);
+                }
+            },
+        }
+    }
+
+    // Case insensitive, as a regex if it is one, otherwise as plain text
+    // (eg while typing a bracket). None for an empty search.
+    fn search_regex(input: &str) -> Option<regex::Regex> {
+        if input.is_empty() {
+            return None;
+        }
+
+        regex::RegexBuilder::new(input)
+            .case_insensitive(true)
+            .build()
+            .or_else(|_| {
+                regex::RegexBuilder::new(&regex::escape(input))
+                    .case_insensitive(true)
+                    .build()
+            })
+            .ok()
+    }
+
+    fn search_matches(&self, key: &GroupKey) -> bool {
+        self.search
+            .as_ref()
+            .is_some_and(|search| search.is_match(&key.to_string()))
+    }
+
+    // Move the selection to a row matching the search, wrapping around the
+    // table. It can be anywhere in the table, not just the rows on screen.
+    fn find(&mut self, direction: Direction) {
+        let keys = self.sorted_keys();
+        if keys.is_empty() || self.search.is_none() {
+            return;
+        }
+
+        let len = keys.len();
+        let selected = self.cursors.last().unwrap().selected;
+        let steps = match direction {
+            Direction::Here => 0..len,
+            Direction::Next | Direction::Previous => 1..len + 1,
+        };
+
+        for step in steps {
+            let i = match direction {
+                Direction::Previous => (selected + len - step % len) % len,
+                _ => (selected + step) % len,
+            };
+
+            if self.search_matches(&keys[i]) {
+                let cursor = self.cursors.last_mut().unwrap();
+                cursor.key = Some(keys[i].clone());
+                cursor.selected = i;
+                return;
+            }
+        }
+    }
+
+    // keys of the table the cursor is in, in the order shown
+    fn sorted_keys(&self) -> Vec<GroupKey> {
+        let ring_buffer = self.ring_buffer.lock().unwrap();
+
+        let grouped =
+            match Self::bucket(&ring_buffer, &self.path).and_then(|rb| rb.grouped.as_ref()) {
+                Some(grouped) => grouped,
+                None => return vec![],
+            };
+
+        let mut rows: Vec<(&GroupKey, &RingBuffer)> = grouped
+            .iter()
+            .filter(|(_, v)| !v.buffer.is_empty())
+            .collect();
+        rows.sort_by(|a, b| Self::row_order(&a, &b));
+
+        rows.into_iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    // as with toggle_group, the rows are different now
+    fn set_filter(&mut self, filter: Option<Filter>) {
+        self.options.lock().unwrap().filter = filter.clone();
+        self.ring_buffer.lock().unwrap().refilter(filter);
+
+        self.path.clear();
+        self.cursors = vec![Cursor::default()];
+    }
+
+    // by rows; the next redraw clamps it to the table, and picks up the key
+    // of the row it lands on
+    fn move_cursor(&mut self, by: isize) {
+        let cursor = self.cursors.last_mut().unwrap();
+        cursor.key = None;
+        cursor.selected = if by < 0 {
+            cursor.selected.saturating_sub(by.unsigned_abs())
+        } else {
+            cursor.selected.saturating_add(by as usize)
+        };
+    }
+
+    // Open a detail view for the selected group, if the current view has
+    // rows; the one highlighted, even if rows have re-ranked since.
+    fn open_selected(&mut self) {
+        use lazysort::SortedBy;
+
+        let ring_buffer = self.ring_buffer.lock().unwrap();
+        let cursor = self.cursors.last().unwrap();
+
+        let key = Self::bucket(&ring_buffer, &self.path)
+            .and_then(|rb| rb.grouped.as_ref())
+            .and_then(|grouped| {
+                let rows: Vec<(&GroupKey, &RingBuffer)> = grouped
+                    .iter()
+                    .filter(|(_, v)| !v.buffer.is_empty())
+                    .collect();
+
+                match cursor.key {
+                    Some(ref key) if Self::row_index(&rows, key).is_some() => Some(key.clone()),
+                    _ => rows
+                        .iter()
+                        .sorted_by(Self::row_order)
+                        .nth(cursor.selected)
+                        .map(|(key, _)| (*key).clone()),
+                }
+            });
+        drop(ring_buffer);
+
+        if let Some(key) = key {
+            self.path.push(key);
+            self.cursors.push(Cursor::default());
+        }
+    }
+
+    // back up a level from a detail view, or clear the search at the top
+    fn close(&mut self) {
+        if self.path.pop().is_some() {
+            self.cursors.pop();
+        } else {
+            self.search = None;
+        }
+    }
+
+    // a comma separated list, as for --columns
+    fn parse_columns(input: &str) -> Result<Vec<Column>, Error> {
+        let columns = input
+            .split(',')
+            .filter(|name| !name.trim().is_empty())
+            .map(|name| name.parse())
+            .collect::<Result<Vec<Column>, Error>>()?;
+
+        if columns.is_empty() {
+            return Err(anyhow!("at least one column is needed"));
+        }
+
+        Ok(columns)
+    }
+
+    fn table_line(
+        &self,
+        columns: &[Column],
+        key: &GroupKey,
+        rr: &RingBuffer,
+        elapsed: f64,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> String {
+        let stats = Self::table_stats(columns, rr, elapsed, now);
         format!(
-            "{reqs:6} {reqs_per_sec:6.2} {hb:>6} {hb_per_sec:>6} {key:width$}",
-            width = (self.cols - 30) as usize,
-            reqs = reqs,
-            reqs_per_sec = reqs / elapsed,
-            hb = Self::humansize(rr.stats.global.bytes as f64),
-            hb_per_sec = Self::humansize(rr.stats.global.bytes as f64 / elapsed),
+            "{} {key:width$}",
+            stats,
+            width = (self.cols as usize).saturating_sub(stats.len() + 1),
             key = key
         )
     }
 
+    // the table columns before the group key, shared with batch mode
+    pub fn table_header(columns: &[Column]) -> String {
+        let headers: Vec<String> = columns.iter().map(|c| c.header()).collect();
+        headers.join(" ")
+    }
+
+    pub fn table_stats(
+        columns: &[Column],
+        rr: &RingBuffer,
+        elapsed: f64,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> String {
+        let values: Vec<String> = columns.iter().map(|c| c.value(rr, elapsed, now)).collect();
+        values.join(" ")
+    }
+
     // All:       638924 reqs ( 182.65/sec)      3433539K ( 981.6K/sec)  (   5.4K/req)
     fn primary_stats_line(&self, stats: &Stats, elapsed: f64, alltime: bool) -> String {
         let reqs_non_zero = std::cmp::max(stats.global.requests, 1) as f64;
@@ -292,7 +1040,25 @@ impl Window {
         )
     }
 
-    fn humansize(bytes: f64) -> String {
+    // Time: min    1ms avg   23ms p50   12ms p95  410ms p99  1.20s max  2.10s
+    pub fn time_line(stats: &Stats) -> String {
+        // nothing to say if the log format doesn't include response times
+        if stats.global.timed_requests == 0 {
+            return String::new();
+        }
+
+        format!(
+            "Time: min {min:>6} avg {avg:>6} p50 {p50:>6} p95 {p95:>6} p99 {p99:>6} max {max:>6}",
+            min = Self::humantime(stats.global.min_time()),
+            avg = Self::humantime(stats.global.avg_time()),
+            p50 = Self::humantime(stats.global.percentile_time(50.0)),
+            p95 = Self::humantime(stats.global.percentile_time(95.0)),
+            p99 = Self::humantime(stats.global.percentile_time(99.0)),
+            max = Self::humantime(stats.global.max_time()),
+        )
+    }
+
+    pub fn humansize(bytes: f64) -> String {
         if bytes > 1073741824.0 {
             format!("{:6.2}G", (bytes / 1073741824.0))
         } else if bytes > 1048576.0 {
@@ -303,19 +1069,57 @@ impl Window {
             format!("{:6.0}B", bytes)
         }
     }
+
+    pub fn humantime(time: Option<std::time::Duration>) -> String {
+        let micros = match time {
+            Some(t) => t.as_micros() as f64,
+            None => return String::from("-"),
+        };
+
+        if micros >= 10_000_000.0 {
+            format!("{:.0}s", micros / 1_000_000.0)
+        } else if micros >= 1_000_000.0 {
+            format!("{:.2}s", micros / 1_000_000.0)
+        } else if micros >= 1000.0 {
+            format!("{:.0}ms", micros / 1000.0)
+        } else {
+            format!("{:.0}us", micros)
+        }
+    }
+}
+
+// label shown before the input
+impl std::fmt::Display for PromptKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Filter => write!(f, "filter (empty to clear)"),
+            Self::Search => write!(f, "search"),
+            Self::Columns => write!(f, "columns, eg reqs,err%,p95"),
+        }
+    }
+}
+
+// for Window::find
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Direction {
+    // the selected row if it matches, else the next
+    Here,
+    Next,
+    Previous,
 }
 
-struct StartedAt(std::time::Instant);
+// time since startup, or since the first request when replaying
+pub struct StartedAt(pub Arc<dyn Clock>);
 
 impl StartedAt {
-    fn elapsed(&self) -> std::time::Duration {
+    pub fn elapsed(&self) -> std::time::Duration {
         self.0.elapsed()
     }
 }
 
 impl std::fmt::Display for StartedAt {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let duration = self.0.elapsed().as_secs();
+        let duration = self.elapsed().as_secs();
 
         let hours = duration / 3600;
         let minutes = duration % 3600 / 60;
@@ -331,3 +1135,102 @@ impl std::fmt::Display for StartedAt {
         write!(f, "{}s", seconds)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn started_at_shows_clock_runtime() {
+        let start = chrono::DateTime::parse_from_rfc3339("2020-05-01T10:00:00Z").unwrap();
+        let clock = Arc::new(ManualClock::new(start.into()));
+        let started_at = StartedAt(Arc::clone(&clock) as Arc<dyn Clock>);
+
+        assert_eq!(started_at.to_string(), "runtime: 0s");
+
+        clock.advance(chrono::Duration::seconds(65));
+        assert_eq!(started_at.to_string(), "runtime: 1m 5s");
+
+        clock.advance(chrono::Duration::hours(2));
+        assert_eq!(started_at.to_string(), "runtime: 2h 1m 5s");
+        assert_eq!(started_at.elapsed().as_secs(), 7265);
+    }
+
+    #[test]
+    fn search_is_a_regex_or_plain_text() {
+        let search = Window::search_regex("^/API/v[12]").unwrap();
+        assert!(search.is_match("/api/v2/users"));
+        assert!(!search.is_match("/static/api/v2"));
+
+        // not a valid regex yet, so taken literally
+        let search = Window::search_regex("/a[").unwrap();
+        assert!(search.is_match("/a[1]"));
+        assert!(!search.is_match("/a1"));
+
+        assert!(Window::search_regex("").is_none());
+    }
+
+    #[test]
+    fn selection_follows_its_group() {
+        use structopt::StructOpt;
+
+        let options = Options::from_iter(&["apachetop"]);
+        let mut rb = RingBuffer::new(Arc::new(Mutex::new(options)), Some(0)).unwrap();
+
+        let push = |rb: &mut RingBuffer, uri: &str| {
+            let mut request = Request::blank();
+            request.uri = String::from(uri);
+            rb.push(Arc::new(request)).unwrap();
+        };
+        for uri in ["/a", "/b", "/b", "/c"].iter() {
+            push(&mut rb, uri);
+        }
+
+        let index = |rb: &RingBuffer, uri: &str| {
+            let rows: Vec<(&GroupKey, &RingBuffer)> = rb.grouped.as_ref().unwrap().iter().collect();
+            Window::row_index(&rows, &GroupKey::URI(String::from(uri)))
+        };
+
+        // /b, then /a and /c tied on requests, so ordered by key
+        assert_eq!(index(&rb, "/b"), Some(0));
+        assert_eq!(index(&rb, "/a"), Some(1));
+        assert_eq!(index(&rb, "/c"), Some(2));
+        assert_eq!(index(&rb, "/d"), None);
+
+        // /a overtakes /b
+        push(&mut rb, "/a");
+        push(&mut rb, "/a");
+        assert_eq!(index(&rb, "/a"), Some(0));
+        assert_eq!(index(&rb, "/b"), Some(1));
+    }
+
+    #[test]
+    fn detail_view_finds_nested_buckets() {
+        use structopt::StructOpt;
+
+        let options = Options::from_iter(&["apachetop", "--group", "uri,status"]);
+        let mut rb = RingBuffer::new(Arc::new(Mutex::new(options)), Some(0)).unwrap();
+
+        for (uri, status) in [("/a", 200), ("/a", 404), ("/a", 200), ("/b", 200)].iter() {
+            let mut request = Request::blank();
+            request.uri = String::from(*uri);
+            request.status_code = *status;
+            rb.push(Arc::new(request)).unwrap();
+        }
+
+        let a = GroupKey::URI(String::from("/a"));
+        let bucket = Window::bucket(&rb, std::slice::from_ref(&a)).unwrap();
+        assert_eq!(bucket.stats.global.requests, 3);
+        assert_eq!(Window::status_codes_line(bucket), "Codes: 200 x 2, 404 x 1");
+
+        let path = [a.clone(), GroupKey::StatusCode(404)];
+        assert_eq!(Window::bucket(&rb, &path).unwrap().buffer.len(), 1);
+
+        // gone from the window, or deeper than the chain
+        assert!(Window::bucket(&rb, &[GroupKey::URI(String::from("/c"))]).is_none());
+        let path = [a, GroupKey::StatusCode(200), GroupKey::StatusCode(200)];
+        assert!(Window::bucket(&rb, &path).is_none());
+    }
+}